@@ -7,7 +7,7 @@ impl MessageHandler for Processor {
     fn on_message(&mut self, message: &[u8]) -> Result<()> {
         self.0 += 1;
         if message[0] == b'F' {
-            println!(
+            log::info!(
                 "Processed {} messages in {} ms",
                 self.0,
                 self.1.elapsed().as_millis()
@@ -15,13 +15,14 @@ impl MessageHandler for Processor {
             panic!("halt receiver");
         };
         if self.0 % 100_000 == 0 {
-            println!("Processed {} messages", self.0);
+            log::info!("Processed {} messages", self.0);
         }
         Ok(())
     }
 }
 
 fn main() {
+    grayarea::log::init(log::LevelFilter::Info).unwrap();
     let started = Instant::now();
     set_message_handler(Box::new(Processor(0, started)));
 }
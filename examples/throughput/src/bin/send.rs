@@ -4,10 +4,15 @@ use rand::{thread_rng, Rng};
 use std::time::Instant;
 
 fn main() {
+    grayarea::log::init(log::LevelFilter::Info).unwrap();
     let n: usize = std::env::args().nth(0).unwrap().parse().unwrap();
     let size: usize = std::env::args().nth(1).unwrap().parse().unwrap();
     let data: Vec<u8> = thread_rng().sample_iter(&Standard).take(size).collect();
-    let mut msg = channel::Message { topic: 0, data };
+    let topic = channel::Channel::topic("local.topic1").unwrap();
+    let mut msg = channel::Message {
+        topic: topic.index(),
+        data,
+    };
     msg.data[0] = b'S';
     let started = Instant::now();
     for _ in 0..n {
@@ -16,8 +21,8 @@ fn main() {
     msg.data[0] = b'F';
     channel::Channel::send_message(&msg);
     let ms = started.elapsed().as_millis();
-    println!("Sent {} messages in {} ms", n + 1, ms);
-    println!(
+    log::info!("Sent {} messages in {} ms", n + 1, ms);
+    log::info!(
         "Message size {} speed {} MiB/s",
         size,
         size as u128 * n as u128 * 1_000 / ms / 1024 / 1024
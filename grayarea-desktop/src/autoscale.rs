@@ -0,0 +1,155 @@
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use grayarea::config::{Autoscale, PartitionBy};
+use grayarea::CHANNEL_SIZE;
+use ipc_orchestrator::message::Message;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Time partitioned replicas are given to pick up messages sent to them before those are scaled
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Routes messages of the function to its active replicas, replicas are scaled
+/// by depth of the function's input queue and lag of the active replicas
+///
+/// Replica which is scaled up gets new input channel, so that its runtime is started.
+/// Replica which is scaled down gets no new messages, its input channel is closed
+/// and its runtime exits once it processed the messages sent to it.
+/// When messages are partitioned by key all the active replicas are drained before
+/// partitions change, so that messages with the same key keep their order.
+/// Draining is given up after `DRAIN_TIMEOUT`, so that replica which is down does not
+/// stall the function.
+pub fn dispatch(
+    function: String,
+    rx: Receiver<Message>,
+    replicas: Vec<UnboundedSender<Receiver<Message>>>,
+    autoscale: Autoscale,
+    partition_by: Option<PartitionBy>,
+) {
+    let mut active = Vec::new();
+    let initial = autoscale.min_replicas.min(replicas.len()).max(1);
+    if !scale_up(&mut active, &replicas, initial) {
+        return;
+    }
+    let mut next = 0;
+    let mut check = Instant::now() + autoscale.interval();
+    loop {
+        match rx.recv_timeout(check.saturating_duration_since(Instant::now())) {
+            Ok(msg) => {
+                let idx = match partition_by.as_ref() {
+                    Some(partition_by) => partition_by.replica(&msg.data, active.len()),
+                    None => {
+                        let idx = next % active.len();
+                        next += 1;
+                        idx
+                    }
+                };
+                if active[idx].send(msg).is_err() {
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if Instant::now() < check {
+            continue;
+        }
+        let depth = rx.len();
+        let lag: Vec<usize> = active.iter().map(Sender::len).collect();
+        let target = autoscale.replicas(depth, &lag).min(replicas.len());
+        if target != active.len() {
+            log::info!(
+                "Scaling function {} from {} to {} replicas: queue depth {}, replicas lag {:?}",
+                function,
+                active.len(),
+                target,
+                depth,
+                lag
+            );
+            if partition_by.is_some() && !drain(&active) {
+                log::warn!(
+                    "Replicas of function {} did not drain in {:?}, messages with the same key might be reordered",
+                    function,
+                    DRAIN_TIMEOUT
+                );
+            }
+            if target > active.len() {
+                if !scale_up(&mut active, &replicas, target) {
+                    return;
+                }
+            } else {
+                // runtimes of closed channels exit once they processed the messages
+                active.truncate(target);
+            }
+        }
+        check = Instant::now() + autoscale.interval();
+    }
+}
+
+/// Starts replicas up to `target` passing them new input channels,
+/// returns `false` once the pipeline is stopped
+fn scale_up(
+    active: &mut Vec<Sender<Message>>,
+    replicas: &[UnboundedSender<Receiver<Message>>],
+    target: usize,
+) -> bool {
+    while active.len() < target {
+        let (tx, rx) = channel::bounded::<Message>(CHANNEL_SIZE);
+        if replicas[active.len()].send(rx).is_err() {
+            return false;
+        }
+        active.push(tx);
+    }
+    true
+}
+
+/// Waits until replicas pick up all the messages sent to them,
+/// returns `false` once `DRAIN_TIMEOUT` expired, e.g. when runtime of replica is down
+fn drain(replicas: &[Sender<Message>]) -> bool {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while replicas.iter().any(|tx| !tx.is_empty()) {
+        if Instant::now() > deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dispatch;
+    use crossbeam::channel;
+    use futures::executor::block_on;
+    use grayarea::config::Autoscale;
+    use ipc_orchestrator::message::Message;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn replicas_are_started_and_stopped() {
+        let autoscale: Autoscale = serde_yaml::from_str(
+            "{min_replicas: 1, max_replicas: 2, scale_up_depth: 5, interval_ms: 10}",
+        )
+        .unwrap();
+        let (tx, rx) = channel::unbounded();
+        let (replicas, mut inputs): (Vec<_>, Vec<_>) =
+            (0..2).map(|_| mpsc::unbounded_channel()).unzip();
+        let dispatcher =
+            std::thread::spawn(move || dispatch("f".to_string(), rx, replicas, autoscale, None));
+        let first = block_on(inputs[0].recv()).unwrap();
+        for _ in 0..20 {
+            let msg = Message {
+                topic: "t".to_string(),
+                data: Vec::new(),
+            };
+            tx.send(msg).unwrap();
+        }
+        // first replica lags behind, so the second one is started
+        let second = block_on(inputs[1].recv()).unwrap();
+        let reader = std::thread::spawn(move || first.iter().count());
+        // once replicas caught up the second one is stopped closing its input
+        let count = second.iter().count();
+        drop(tx);
+        dispatcher.join().unwrap();
+        assert_eq!(reader.join().unwrap() + count, 20);
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Context, Result};
+use grayarea::header;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Extension of files of stored messages
+const STORED: &str = "dlq";
+/// Extension of files claimed for republishing
+const CLAIMED: &str = "republishing";
+
+/// Store of messages published to dead letter topics which are not consumed by any function
+///
+/// Messages of every topic are appended to `<dir>/<topic>.dlq` as frames
+/// of u32 little endian length followed by message envelope.
+/// Messages are claimed for republishing by renaming the file to `<topic>.republishing`,
+/// which is removed once all of its messages are republished or stored back,
+/// so that messages of interrupted republishing are claimed again.
+#[derive(Clone)]
+pub struct DeadLetters {
+    dir: PathBuf,
+}
+
+/// Stored messages of dead letter topic claimed for republishing
+pub struct Claim {
+    pub topic: String,
+    pub messages: Vec<Vec<u8>>,
+    path: PathBuf,
+}
+
+impl DeadLetters {
+    pub fn new(dir: &Path) -> Self {
+        DeadLetters {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Appends message envelope to the file of the topic
+    pub fn append(&self, topic: &str, envelope: &[u8]) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Could not create dead letters directory {:?}", self.dir))?;
+        let path = self.path(topic, STORED);
+        // frame is written at once, so that file is never left with partial length
+        let mut frame = Vec::with_capacity(envelope.len() + 4);
+        frame.extend_from_slice(&(envelope.len() as u32).to_le_bytes());
+        frame.extend_from_slice(envelope);
+        append_to(&path, &frame)
+    }
+
+    /// Claims stored messages of all the topics for republishing,
+    /// including messages claimed before and not released
+    pub fn claim(&self) -> Result<Vec<Claim>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let mut topics = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(topic) if path.extension() == Some(STORED.as_ref()) => {
+                    let claimed = self.path(topic, CLAIMED);
+                    if claimed.exists() {
+                        // claimed before, stored messages are added to the claim
+                        append_to(&claimed, &fs::read(&path)?)?;
+                        fs::remove_file(&path)?;
+                    } else {
+                        fs::rename(&path, &claimed)
+                            .with_context(|| format!("Could not claim {:?}", path))?;
+                    }
+                    topics.push(topic.to_string());
+                }
+                Some(topic) if path.extension() == Some(CLAIMED.as_ref()) => {
+                    topics.push(topic.to_string())
+                }
+                _ => (),
+            }
+        }
+        topics.sort();
+        topics.dedup();
+        topics
+            .into_iter()
+            .map(|topic| {
+                let path = self.path(&topic, CLAIMED);
+                let messages = read_frames(&path)?;
+                Ok(Claim {
+                    topic,
+                    messages,
+                    path,
+                })
+            })
+            .collect()
+    }
+
+    /// Removes claimed messages, those shall be republished or stored back by then
+    pub fn release(&self, claim: Claim) -> Result<()> {
+        fs::remove_file(&claim.path)
+            .with_context(|| format!("Could not remove claimed dead letters {:?}", claim.path))
+    }
+
+    fn path(&self, topic: &str, extension: &str) -> PathBuf {
+        let name = topic.replace(
+            |c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '.',
+            "_",
+        );
+        self.dir.join(format!("{}.{}", name, extension))
+    }
+}
+
+fn append_to(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open dead letters file {:?}", path))?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+/// Reads envelopes of dead letters file
+fn read_frames(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let bytes = fs::read(path)?;
+    let mut messages = Vec::new();
+    let mut rest = &bytes[..];
+    while rest.len() >= 4 {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&rest[..4]);
+        let len = u32::from_le_bytes(len) as usize;
+        let envelope = rest[4..]
+            .get(..len)
+            .ok_or_else(|| anyhow!("Dead letters file {:?} has truncated message", path))?;
+        messages.push(envelope.to_vec());
+        rest = &rest[4 + len..];
+    }
+    Ok(messages)
+}
+
+/// Restores dead-lettered envelope, returns original topic and envelope to publish there
+pub fn restore(envelope: &[u8]) -> Result<(String, Vec<u8>)> {
+    let (headers, data) = header::decode(envelope)?;
+    let source = headers.source.clone();
+    let headers = headers
+        .restore()
+        .ok_or_else(|| anyhow!("Message of {} was not dead-lettered", source))?;
+    Ok((headers.topic.clone(), header::encode(&headers, data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{restore, DeadLetters};
+    use grayarea::header::{self, ContentType, Headers};
+
+    #[test]
+    fn store_and_republish() {
+        let dir = std::env::temp_dir().join(format!("grayarea-dlq-{}", std::process::id()));
+        let store = DeadLetters::new(&dir);
+        let headers = Headers::new("send", "topic1", 1, ContentType::Binary);
+        let dead = headers
+            .clone()
+            .dead_letter("failed", "receive", "trapped", 1);
+        store
+            .append("failed", &header::encode(&dead, b"one").unwrap())
+            .unwrap();
+        store
+            .append("failed", &header::encode(&dead, b"two").unwrap())
+            .unwrap();
+
+        let mut claims = store.claim().unwrap();
+        assert_eq!(claims.len(), 1);
+        let claim = claims.remove(0);
+        assert_eq!(claim.topic, "failed");
+        assert_eq!(claim.messages.len(), 2);
+        let (topic, envelope) = restore(&claim.messages[1]).unwrap();
+        assert_eq!(topic, "topic1");
+        assert_eq!(header::decode(&envelope).unwrap(), (headers, &b"two"[..]));
+        store.release(claim).unwrap();
+        assert!(store.claim().unwrap().is_empty());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreleased_claim_is_claimed_again() {
+        let dir = std::env::temp_dir().join(format!("grayarea-dlq-claim-{}", std::process::id()));
+        let store = DeadLetters::new(&dir);
+        store.append("failed", b"one").unwrap();
+        let claims = store.claim().unwrap();
+        assert_eq!(claims[0].messages, vec![b"one".to_vec()]);
+        // republishing is interrupted, while new message is stored
+        store.append("failed", b"two").unwrap();
+        let mut claims = store.claim().unwrap();
+        assert_eq!(claims.len(), 1);
+        let claim = claims.remove(0);
+        assert_eq!(claim.messages, vec![b"one".to_vec(), b"two".to_vec()]);
+        store.release(claim).unwrap();
+        assert!(store.claim().unwrap().is_empty());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -1,2 +1,12 @@
+mod autoscale;
+mod dead_letter;
 mod options;
-pub use options::Opt;
+mod routing;
+mod supervisor;
+mod validate;
+pub use autoscale::dispatch;
+pub use dead_letter::{restore, Claim, DeadLetters};
+pub use options::{Command, Opt};
+pub use routing::{consumer_groups, fan_out, Group};
+pub use supervisor::Runtime;
+pub use validate::{validate, Problem};
@@ -1,89 +1,324 @@
 #![allow(clippy::unnecessary_mut_passed)]
 
 use crossbeam::channel;
-use futures::future::try_join_all;
-use grayarea_desktop::Opt;
-use ipc_orchestrator::{message::Message, orchestrator};
+use futures::future::{join_all, try_join_all, FutureExt};
+use grayarea::config::{Input, Output, PartitionBy, MODULE_CONFIG_ENV_VAR, REPLICA_ENV_VAR};
+use grayarea::connection::{ConnectionEvent, CONNECTION_TOPIC};
+use grayarea::log::{LogRecord, LOG_TOPIC};
+use grayarea::CHANNEL_SIZE;
+use grayarea_desktop::{
+    consumer_groups, dispatch, fan_out, restore, validate, Claim, Command as Mode, DeadLetters,
+    Group, Opt, Problem, Runtime,
+};
+use ipc_orchestrator::message::Message;
 use std::collections::HashMap;
 use structopt::StructOpt;
 use tokio::process::Command;
-use grayarea::config::Input;
-
-const CHANNEL_SIZE: usize = 10;
-
-type TopicChannel = (channel::Sender<Message>, channel::Receiver<Message>);
+use tokio::sync::mpsc;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     init_log_engine();
     let opt = Opt::from_args();
-    let config = opt.load_config().await?;
-    // Load all modules configs
-    let modules = try_join_all(config.functions.iter().map(|module| module.load_config())).await?;
-    // Create crossbeam channels for all input topics
-    let _in_topics: HashMap<String, TopicChannel> = modules
-        .iter()
-        .filter_map(|module| {
-            module
-                .input
-                .as_ref()
-                .map(|input| (input.topic.clone(), channel::bounded(CHANNEL_SIZE)))
-        })
-        .collect();
+    // Functions of malformed pipeline config can't be checked any further
+    let config = match opt.load_config().await? {
+        Ok(config) => config,
+        Err(problem) => return report(&[problem]),
+    };
+    // Load all modules configs, every config which can't be loaded is a problem
+    let loaded = join_all(config.functions.iter().map(|module| module.load_config())).await;
+    let mut problems = Vec::new();
+    let mut modules = Vec::new();
+    for (i, (function, module)) in config.functions.iter().zip(loaded).enumerate() {
+        match module {
+            Ok(module) => modules.push(module),
+            Err(err) => problems.push(Problem::load(opt.config_path(), i, function, &err)),
+        }
+    }
+    // Validate pipeline topology before starting anything
+    if problems.is_empty() {
+        problems = validate(opt.config_path(), &config, &modules);
+    }
+    if !problems.is_empty() {
+        return report(&problems);
+    }
+    if let Some(Mode::Validate) = opt.command {
+        log::info!("Pipeline configuration is valid");
+        return Ok(());
+    }
+    // Stored dead letters are claimed before new ones might be stored
+    let dead_letters = DeadLetters::new(&opt.dead_letters);
+    let republished = match opt.command {
+        Some(Mode::Republish) => dead_letters.claim()?,
+        _ => Vec::new(),
+    };
 
-    // Start out commands
-    let mut orchestrator = orchestrator().ipc(true).rust_backtrace(opt.debug);
-    for stage in config.functions.iter() {
-        let mut cmd = if opt.debug {
-            let mut cmd = Command::new("cargo");
-            cmd.arg("run").arg("--package=grayarea-runtime");
-            cmd
-        } else {
-            Command::new("grayarea-runtime")
+    // Every consumer group of a topic is a channel, functions of the same group
+    // compete for messages. Producers send to the topic, which is fanned out to all its groups.
+    let mut in_topics = HashMap::new();
+    let mut group_topics = HashMap::new();
+    for (topic, groups) in consumer_groups(&modules) {
+        let channels: Vec<_> = groups
+            .into_iter()
+            .map(|group| (group, channel::bounded::<Message>(CHANNEL_SIZE)))
+            .collect();
+        let tx = match channels.as_slice() {
+            [(_, (tx, _))] => tx.clone(),
+            _ => {
+                let (tx, rx) = channel::bounded::<Message>(CHANNEL_SIZE);
+                let groups = channels
+                    .iter()
+                    .map(|(group, (tx, _))| (group.clone(), tx.clone()))
+                    .collect();
+                let topic = topic.clone();
+                std::thread::spawn(move || fan_out(&topic, rx, groups));
+                tx
+            }
         };
-
-        orchestrator
-            .start(&stage.name, cmd.arg(&stage.config))
-            .expect("failed to start process");
+        for (group, (_, rx)) in channels {
+            group_topics.insert((topic.clone(), group), rx);
+        }
+        in_topics.insert(topic, tx);
     }
-
-    // Estiblish connections between commands
-    let mut orchestra = orchestrator.connect().await?;
-    for module in modules.into_iter() {
+    // Log records of all the functions are collected into the desktop log
+    let (log_tx, log_rx) = channel::unbounded::<Message>();
+    // Connection events of input streams are logged along with them
+    let (event_tx, event_rx) = channel::unbounded::<Message>();
+    // Dead letter topics without consumers are stored to be republished later
+    let (dead_tx, dead_rx) = channel::unbounded::<Message>();
+    let mut runtimes = Vec::new();
+    for (module, function) in modules.into_iter().zip(config.functions.iter()) {
+        let names = function.replica_names();
         // Connect module's outputs to relevant topics
-        // Will fail if some output topics have not relevant sinks
-        /*
-        if let Ok(topics) = module.topics() {
-            let mut out_topics = HashMap::new();
+        // topology is validated, so every output topic has a consumer
+        let mut out_topics = HashMap::new();
+        out_topics.insert(LOG_TOPIC.to_string(), log_tx.clone());
+        out_topics.insert(CONNECTION_TOPIC.to_string(), event_tx.clone());
+        if let Some(Output { topics }) = module.output.as_ref() {
             for name in topics {
-                let topic = in_topics.get(&name).ok_or_else(|| {
-                    anyhow::anyhow!("topic {} is not pointing to any function input", name)
-                })?;
-                out_topics.insert(name, topic.0.clone());
+                if let Some(tx) = in_topics.get(name) {
+                    out_topics.insert(name.clone(), tx.clone());
+                }
             }
-            orchestra.forward_bridge_rx(&module.name, out_topics)?;
         }
-        // Connect module's input to topic
-        if let Some(Some(topic)) = module
+        if let Some(topic) = module.dead_letter.as_ref() {
+            let tx = match in_topics.get(topic) {
+                Some(tx) => tx.clone(),
+                None => dead_tx.clone(),
+            };
+            out_topics.insert(topic.clone(), tx);
+        }
+        // Connect module's input to topics, multiple topics are merged into one channel
+        let group = Group::of(&module);
+        let mut inputs: Vec<_> = module
             .input
-            .as_ref()
-            .map(|input| in_topics.get(&input.topic))
-        {
-            orchestra.forward_bridge_tx(&module.name, topic.1.clone())?;
+            .iter()
+            .flat_map(Input::all_topics)
+            .filter_map(|topic| {
+                let group = group.clone()?;
+                group_topics.get(&(topic.clone(), group)).cloned()
+            })
+            .collect();
+        let input = match inputs.len() {
+            0 => None,
+            1 => Some(inputs.remove(0)),
+            _ => {
+                let (tx, rx) = channel::bounded::<Message>(CHANNEL_SIZE);
+                std::thread::spawn(move || merge_topics(inputs, tx));
+                Some(rx)
+            }
+        };
+        // Runtime receives resolved module config instead of reading it on its own
+        let yaml = function.load_yaml().await?;
+        // Replicas compete for messages of the function unless those are partitioned by key
+        // or function is autoscaled, runtimes of autoscaled function are started on scale up
+        let (autoscale, partition_by) = (function.autoscale.clone(), function.partition_by.clone());
+        match (input, autoscale, partition_by) {
+            (Some(rx), Some(autoscale), partition_by) => {
+                let (txs, rxs): (Vec<_>, Vec<_>) =
+                    names.iter().map(|_| mpsc::unbounded_channel()).unzip();
+                let name = function.name.clone();
+                std::thread::spawn(move || dispatch(name, rx, txs, autoscale, partition_by));
+                for (name, inputs) in names.iter().zip(rxs) {
+                    let cmd = runtime_command(opt.debug, &yaml, name);
+                    let runtime = Runtime::new(name, cmd, &module);
+                    runtimes.push(runtime.supervise_scaled(inputs, out_topics.clone()).boxed());
+                }
+            }
+            (input, _, partition_by) => {
+                let inputs: Vec<_> = match (input, partition_by) {
+                    (Some(rx), Some(partition_by)) if names.len() > 1 => {
+                        let (txs, rxs): (Vec<_>, Vec<_>) = names
+                            .iter()
+                            .map(|_| channel::bounded::<Message>(CHANNEL_SIZE))
+                            .unzip();
+                        std::thread::spawn(move || partition(rx, partition_by, txs));
+                        rxs.into_iter().map(Some).collect()
+                    }
+                    (input, _) => names.iter().map(|_| input.clone()).collect(),
+                };
+                for (name, input) in names.iter().zip(inputs) {
+                    let cmd = runtime_command(opt.debug, &yaml, name);
+                    let runtime = Runtime::new(name, cmd, &module);
+                    runtimes.push(runtime.supervise(input, out_topics.clone()).boxed());
+                }
+            }
+        }
+    }
+    drop(log_tx);
+    drop(event_tx);
+    drop(dead_tx);
+    std::thread::spawn(move || collect_logs(log_rx));
+    std::thread::spawn(move || collect_connection_events(event_rx));
+    let store = dead_letters.clone();
+    std::thread::spawn(move || store_dead_letters(store, dead_rx));
+    if !republished.is_empty() {
+        std::thread::spawn(move || republish(republished, in_topics, dead_letters));
+    }
+
+    // Failed runtimes are started again, so only failure of critical function gets here.
+    // Killing it hard since forwarding threads of other functions might still run
+    if let Err(err) = try_join_all(runtimes).await {
+        log::error!("{:#}", err);
+        std::process::exit(1);
+    }
+    log::info!("All the functions finished");
+    Ok(())
+}
+
+/// Logs problems of pipeline configuration, which is not started then
+fn report(problems: &[Problem]) -> anyhow::Result<()> {
+    for problem in problems.iter() {
+        log::error!("{}", problem);
+    }
+    Err(anyhow::anyhow!(
+        "Pipeline configuration has {} problem(s)",
+        problems.len()
+    ))
+}
+
+/// Command starting runtime of the function replica
+fn runtime_command(debug: bool, yaml: &str, replica: &str) -> Command {
+    let mut cmd = if debug {
+        let mut cmd = Command::new("cargo");
+        cmd.arg("run").arg("--package=grayarea-runtime");
+        cmd.env("RUST_BACKTRACE", "1");
+        cmd
+    } else {
+        Command::new("grayarea-runtime")
+    };
+    cmd.env(MODULE_CONFIG_ENV_VAR, yaml)
+        .env(REPLICA_ENV_VAR, replica);
+    cmd
+}
+
+/// Writes log records of functions to the desktop log, targeted by function name
+fn collect_logs(rx: channel::Receiver<Message>) {
+    for msg in rx.iter() {
+        match LogRecord::from_bytes(&msg.data) {
+            Ok(record) => log::log!(target: &record.function, record.level, "{}", record),
+            Err(err) => log::error!("Malformed log record: {}", err),
+        }
+    }
+}
+
+/// Writes lifecycle events of input streams to the desktop log, targeted by function name
+fn collect_connection_events(rx: channel::Receiver<Message>) {
+    for msg in rx.iter() {
+        match ConnectionEvent::from_bytes(&msg.data) {
+            Ok(event) => log::log!(target: &event.function, event.level(), "{}", event),
+            Err(err) => log::error!("Malformed connection event: {}", err),
+        }
+    }
+}
+
+/// Routes messages to replicas by key, so that messages with the same key keep their order
+fn partition(
+    rx: channel::Receiver<Message>,
+    partition_by: PartitionBy,
+    replicas: Vec<channel::Sender<Message>>,
+) {
+    for msg in rx.iter() {
+        let idx = partition_by.replica(&msg.data, replicas.len());
+        if replicas[idx].send(msg).is_err() {
+            return;
+        }
+    }
+}
+
+/// Forwards messages of all the topics into one channel until all of them are closed
+fn merge_topics(mut topics: Vec<channel::Receiver<Message>>, tx: channel::Sender<Message>) {
+    while !topics.is_empty() {
+        let mut select = channel::Select::new();
+        for rx in topics.iter() {
+            select.recv(rx);
         }
-        */
-        if let Some(Input {topic,..}) = module.input.as_ref()
-        {
-            orchestra.route_topic_to_bridge(&topic, &module.name)?;
+        let op = select.select();
+        let idx = op.index();
+        match op.recv(&topics[idx]) {
+            Ok(msg) => {
+                if tx.send(msg).is_err() {
+                    return;
+                }
+            }
+            Err(_) => {
+                topics.remove(idx);
+            }
         }
     }
-    orchestra.pipe_routes()?;
+}
 
-    // Killing it hard since some spawned futures might still run
-    match orchestra.run().await {
-        Err(_) => std::process::exit(1),
-        _ => Ok(()),
+/// Stores messages of dead letter topics without consumers
+fn store_dead_letters(store: DeadLetters, rx: channel::Receiver<Message>) {
+    for msg in rx.iter() {
+        match store.append(&msg.topic, &msg.data) {
+            Ok(()) => log::warn!("Message dead-lettered to {} is stored", msg.topic),
+            Err(err) => log::error!("Dead-lettered message to {} is lost: {}", msg.topic, err),
+        }
+    }
+}
+
+/// Publishes claimed dead-lettered messages to their original topics,
+/// messages of topics without consumers are stored back.
+/// Claim is released once all of its messages are either republished or stored back,
+/// otherwise all of them are republished again next time.
+fn republish(
+    claims: Vec<Claim>,
+    topics: HashMap<String, channel::Sender<Message>>,
+    store: DeadLetters,
+) {
+    let mut count = 0;
+    for claim in claims {
+        let mut kept = true;
+        for envelope in claim.messages.iter() {
+            let republished = restore(envelope).and_then(|(topic, data)| {
+                let tx = topics.get(&topic).ok_or_else(|| {
+                    anyhow::anyhow!("topic {} is not consumed by any function", topic)
+                })?;
+                tx.send(Message { topic, data })
+                    .map_err(|_| anyhow::anyhow!("pipeline is stopped"))
+            });
+            match republished {
+                Ok(()) => count += 1,
+                Err(err) => {
+                    log::error!("Could not republish message of {}: {}", claim.topic, err);
+                    if let Err(err) = store.append(&claim.topic, envelope) {
+                        log::error!("Could not store back message of {}: {}", claim.topic, err);
+                        kept = false;
+                    }
+                }
+            }
+        }
+        if !kept {
+            log::warn!(
+                "Dead letters of {} are kept to be republished again",
+                claim.topic
+            );
+        } else if let Err(err) = store.release(claim) {
+            log::error!("{:#}", err);
+        }
     }
+    log::info!("Republished {} dead-lettered message(s)", count);
 }
 
 fn init_log_engine() {
@@ -1,6 +1,7 @@
+use crate::Problem;
 use anyhow::Context;
 use grayarea::config::PipelineConfig;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use tokio::fs::read;
 
@@ -15,13 +16,36 @@ pub struct Opt {
     config: PathBuf,
     #[structopt(short = "d", long = "debug")]
     pub debug: bool,
+    /// Directory of messages published to dead letter topics not consumed by any function
+    #[structopt(
+        long = "dead-letters",
+        parse(from_os_str),
+        default_value = "dead-letters"
+    )]
+    pub dead_letters: PathBuf,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Validate pipeline topology and exit without starting it
+    Validate,
+    /// Start pipeline republishing stored dead-lettered messages to their original topics,
+    /// pipeline keeps running once those are republished
+    Republish,
 }
 
 impl Opt {
-    pub async fn load_config(&self) -> anyhow::Result<PipelineConfig> {
+    /// Reads pipeline config, malformed config is returned as a problem
+    pub async fn load_config(&self) -> anyhow::Result<Result<PipelineConfig, Problem>> {
         let buf = read(self.config.clone())
             .await
             .with_context(|| format!("Could not read config at {:?}", self.config))?;
-        Ok(serde_yaml::from_slice(buf.as_slice()).expect("Malformed config"))
+        let config = serde_yaml::from_slice(buf.as_slice());
+        Ok(config.map_err(|err| Problem::parse(&self.config, &err)))
+    }
+    pub fn config_path(&self) -> &Path {
+        &self.config
     }
 }
@@ -0,0 +1,172 @@
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use grayarea::config::ModuleConfig;
+use ipc_orchestrator::message::Message;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Consumer group of a topic
+///
+/// Functions in the same queue share messages of the topic,
+/// every group gets its own copy of every message.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Group {
+    Queue(String),
+    /// Function without queue consumes all the messages on its own
+    Function(String),
+}
+
+impl Group {
+    /// Consumer group of the function, `None` if it has no input
+    pub fn of(module: &ModuleConfig) -> Option<Self> {
+        module
+            .input
+            .as_ref()
+            .map(|input| match input.queue.as_ref() {
+                Some(queue) => Group::Queue(queue.clone()),
+                None => Group::Function(module.name.clone()),
+            })
+    }
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Group::Queue(queue) => write!(f, "queue {}", queue),
+            Group::Function(function) => write!(f, "function {}", function),
+        }
+    }
+}
+
+/// Forwards every message of the topic to all its consumer groups until the topic is closed,
+/// returns number of messages dropped for every group.
+///
+/// Group which falls behind does not hold back the others, messages are dropped for the group
+/// while its channel is full. Dropped messages are logged once the group catches up.
+pub fn fan_out(
+    topic: &str,
+    rx: Receiver<Message>,
+    groups: Vec<(Group, Sender<Message>)>,
+) -> Vec<u64> {
+    let mut dropped = vec![0; groups.len()];
+    let mut behind = vec![0; groups.len()];
+    for msg in rx.iter() {
+        for (idx, (group, tx)) in groups.iter().enumerate() {
+            let msg = Message {
+                topic: msg.topic.clone(),
+                data: msg.data.clone(),
+            };
+            match tx.try_send(msg) {
+                Ok(()) if behind[idx] > 0 => {
+                    log::warn!(
+                        "{} caught up with {}, {} message(s) dropped",
+                        group,
+                        topic,
+                        behind[idx]
+                    );
+                    behind[idx] = 0;
+                }
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    if behind[idx] == 0 {
+                        log::warn!("{} falls behind {}, dropping messages", group, topic);
+                    }
+                    behind[idx] += 1;
+                    dropped[idx] += 1;
+                }
+                // group is gone along with the pipeline
+                Err(TrySendError::Disconnected(_)) => (),
+            }
+        }
+    }
+    dropped
+}
+
+/// Consumer groups of every input topic
+pub fn consumer_groups(modules: &[ModuleConfig]) -> BTreeMap<String, BTreeSet<Group>> {
+    let mut groups: BTreeMap<String, BTreeSet<Group>> = BTreeMap::new();
+    for module in modules.iter() {
+        if let (Some(input), Some(group)) = (module.input.as_ref(), Group::of(module)) {
+            for topic in input.all_topics() {
+                groups
+                    .entry(topic.clone())
+                    .or_default()
+                    .insert(group.clone());
+            }
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{consumer_groups, fan_out, Group};
+    use crossbeam::channel;
+    use futures::executor::block_on;
+    use grayarea::config::{ModuleConfig, PipelineConfig};
+    use ipc_orchestrator::message::Message;
+    use std::path::Path;
+
+    /// Loads module configs of example pipeline, paths are relative to the repository root
+    fn example(pipeline: &str) -> Vec<ModuleConfig> {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        let config: PipelineConfig =
+            serde_yaml::from_slice(&std::fs::read(root.join(pipeline)).unwrap()).unwrap();
+        config
+            .functions
+            .iter()
+            .map(|function| match function.config_file() {
+                Some(file) => ModuleConfig::from_yaml(&std::fs::read(root.join(file)).unwrap()),
+                None => block_on(function.load_config()),
+            })
+            .collect::<anyhow::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn competing_consumers() {
+        let groups = consumer_groups(&example("examples/throughput/functions_concur.yml"));
+        let expected = vec![Group::Queue("workers".into())];
+        assert_eq!(
+            groups["local.topic1"].iter().cloned().collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn broadcast_consumers() {
+        let groups = consumer_groups(&example("examples/throughput/functions_fanout.yml"));
+        let expected = vec![
+            Group::Function("checksum".into()),
+            Group::Function("receive".into()),
+        ];
+        assert_eq!(
+            groups["local.topic1"].iter().cloned().collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn slow_group_does_not_block() {
+        let (tx, rx) = channel::unbounded();
+        let (slow_tx, slow_rx) = channel::bounded(1);
+        let (fast_tx, fast_rx) = channel::bounded(10);
+        let groups = vec![
+            (Group::Function("slow".into()), slow_tx),
+            (Group::Queue("fast".into()), fast_tx),
+        ];
+        for i in 0..5u8 {
+            tx.send(Message {
+                topic: "topic".into(),
+                data: vec![i],
+            })
+            .unwrap();
+        }
+        drop(tx);
+        assert_eq!(fan_out("topic", rx, groups), vec![4, 0]);
+        let data = |rx: channel::Receiver<Message>| -> Vec<u8> {
+            rx.try_iter().map(|msg| msg.data[0]).collect()
+        };
+        assert_eq!(data(fast_rx), vec![0, 1, 2, 3, 4]);
+        assert_eq!(data(slow_rx), vec![0]);
+    }
+}
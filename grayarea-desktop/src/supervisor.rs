@@ -0,0 +1,351 @@
+use anyhow::{anyhow, Context, Result};
+use crossbeam::channel;
+use futures::channel::oneshot;
+use grayarea::config::{ModuleConfig, Supervise};
+use ipc_channel::ipc::{IpcOneShotServer, IpcSender};
+use ipc_orchestrator::{message::Message, Channel, Receiver, Sender, IPC_SERVER_ENV_VAR};
+use std::collections::{HashMap, VecDeque};
+use std::process::{ExitStatus, Stdio};
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{ChildStdout, Command};
+use tokio::sync::mpsc;
+
+/// Runtime process of the function replica
+///
+/// Runtime restarts failed function on its own according to the function's restart policy,
+/// runtime process which exited with failure is started again with `supervise` backoff.
+/// Routes of the replica are kept meanwhile and its incoming messages are buffered.
+/// Failure of `critical` function is returned instead, which stops the whole pipeline.
+pub struct Runtime {
+    name: String,
+    cmd: Command,
+    critical: bool,
+    supervise: Supervise,
+}
+
+impl Runtime {
+    pub fn new(name: &str, mut cmd: Command, module: &ModuleConfig) -> Self {
+        cmd.kill_on_drop(true).stdout(Stdio::piped());
+        Runtime {
+            name: name.to_string(),
+            cmd,
+            critical: module.critical,
+            supervise: module.supervise.clone(),
+        }
+    }
+
+    /// Runs the runtime process until it finishes, starting it again whenever it fails.
+    ///
+    /// Messages of `input` are delivered to the runtime, messages it publishes are routed
+    /// to `output` channels by topic. Runtime finishes once `input` is closed and
+    /// it processed the messages sent to it.
+    pub async fn supervise(
+        mut self,
+        input: Option<channel::Receiver<Message>>,
+        output: HashMap<String, channel::Sender<Message>>,
+    ) -> Result<()> {
+        self.run_supervised(input, &output).await
+    }
+
+    /// Runs the runtime process of autoscaled replica whenever it is scaled up,
+    /// until all the pipeline inputs are gone.
+    ///
+    /// Replica gets new `inputs` channel every time it is scaled up, once the channel
+    /// is closed runtime processes messages sent to it and exits.
+    pub async fn supervise_scaled(
+        mut self,
+        mut inputs: mpsc::UnboundedReceiver<channel::Receiver<Message>>,
+        output: HashMap<String, channel::Sender<Message>>,
+    ) -> Result<()> {
+        while let Some(input) = inputs.recv().await {
+            log::info!("runtime {} is scaled up", self.name);
+            self.run_supervised(Some(input), &output).await?;
+        }
+        Ok(())
+    }
+
+    async fn run_supervised(
+        &mut self,
+        input: Option<channel::Receiver<Message>>,
+        output: &HashMap<String, channel::Sender<Message>>,
+    ) -> Result<()> {
+        // every started process gets its own IPC sender
+        let bridges = input.map(|input| {
+            let (bridges, bridges_rx) = channel::unbounded::<Sender>();
+            let name = self.name.clone();
+            let buffer = self.supervise.buffer;
+            std::thread::spawn(move || forward_input(&name, input, bridges_rx, buffer));
+            bridges
+        });
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            let status = self.run(bridges.as_ref(), output).await?;
+            if status.success() {
+                log::info!("runtime {} finished", self.name);
+                return Ok(());
+            }
+            if self.critical {
+                return Err(anyhow!(
+                    "runtime {} of critical function failed with {}",
+                    self.name,
+                    status
+                ));
+            }
+            // runtime which ran for a while starts over from the initial backoff
+            if started.elapsed() > self.supervise.max_backoff() {
+                failures = 0;
+            }
+            let backoff = self.supervise.backoff(failures);
+            log::warn!(
+                "runtime {} failed with {}, restarting in {:?}",
+                self.name,
+                status,
+                backoff
+            );
+            tokio::time::delay_for(backoff).await;
+            failures += 1;
+        }
+    }
+
+    /// Starts runtime process once and routes its messages until it exits
+    async fn run(
+        &mut self,
+        bridges: Option<&channel::Sender<Sender>>,
+        output: &HashMap<String, channel::Sender<Message>>,
+    ) -> Result<ExitStatus> {
+        let (server, server_name) =
+            IpcOneShotServer::<Channel>::new().context("Failed to start IPC server")?;
+        self.cmd.env(IPC_SERVER_ENV_VAR, &server_name);
+        log::debug!("Starting runtime {} {:?}", self.name, self.cmd);
+        let mut child = self
+            .cmd
+            .spawn()
+            .with_context(|| format!("Could not start runtime {}", self.name))?;
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(log_output(stdout, self.name.clone()));
+        }
+        // accepting blocks until runtime connects, so it is done in its own thread
+        let (accepted_tx, accepted) = oneshot::channel();
+        std::thread::spawn(move || {
+            let _ = accepted_tx.send(server.accept());
+        });
+        let accepted = tokio::select! {
+            accepted = accepted => accepted,
+            status = &mut child => {
+                // runtime exited before it connected, closing connection unblocks the server
+                let _ = IpcSender::<Channel>::connect(server_name);
+                return Ok(status?);
+            }
+        };
+        let (_, channel) =
+            accepted?.map_err(|err| anyhow!("Runtime {} failed to connect: {}", self.name, err))?;
+        let (tx, rx) = channel.split()?;
+        // runtime finishes once its sender is closed
+        let _idle = match bridges {
+            Some(bridges) => {
+                // sender is dropped right away when input of the runtime is closed already
+                let _ = bridges.send(tx);
+                None
+            }
+            // runtime without input keeps its sender until it exits
+            None => Some(tx),
+        };
+        let name = self.name.clone();
+        let output = output.clone();
+        std::thread::spawn(move || route_output(&name, rx, output));
+        Ok(child.await?)
+    }
+}
+
+/// Delivers messages to the current process of the runtime until `input` is closed
+///
+/// While runtime is down up to `buffer` messages are kept for its next process, the oldest one
+/// is dropped once buffer is full, so that producers of the topic are not held back.
+/// Message which failed to send is kept, the ones process received but did not process are lost.
+fn forward_input(
+    name: &str,
+    input: channel::Receiver<Message>,
+    bridges: channel::Receiver<Sender>,
+    buffer: usize,
+) {
+    let mut pending = VecDeque::new();
+    let mut current = None;
+    let mut open = true;
+    loop {
+        if let Some(tx) = current.as_ref() {
+            if !deliver(tx, &mut pending) {
+                log::warn!("runtime {} is down, buffering its messages", name);
+                current = None;
+            }
+        }
+        // runtime finishes once it got all the messages and its sender is dropped
+        if !open && pending.is_empty() {
+            return;
+        }
+        let mut select = channel::Select::new();
+        let bridge = select.recv(&bridges);
+        if open {
+            select.recv(&input);
+        }
+        let op = select.select();
+        if op.index() == bridge {
+            match op.recv(&bridges) {
+                // process started again, previous one is gone
+                Ok(tx) => current = Some(tx),
+                Err(_) => return,
+            }
+            continue;
+        }
+        match op.recv(&input) {
+            Ok(msg) => {
+                if pending.len() >= buffer && pending.pop_front().is_some() {
+                    log::warn!("buffer of runtime {} is full, message is dropped", name);
+                }
+                pending.push_back(msg);
+            }
+            Err(_) => open = false,
+        }
+    }
+}
+
+/// Sends pending messages to the runtime process,
+/// returns `false` once process is gone keeping the message which failed to send
+fn deliver(tx: &Sender, pending: &mut VecDeque<Message>) -> bool {
+    while let Some(msg) = pending.pop_front() {
+        if tx.send(msg.clone()).is_err() {
+            pending.push_front(msg);
+            return false;
+        }
+    }
+    true
+}
+
+/// Routes messages published by the runtime process to topics until it exits,
+/// message to topic without route is dropped
+fn route_output(name: &str, rx: Receiver, output: HashMap<String, channel::Sender<Message>>) {
+    while let Ok(msg) = rx.recv() {
+        match output.get(&msg.topic) {
+            Some(tx) => {
+                let _ = tx.send(msg);
+            }
+            None => log::error!(
+                "runtime {} published to unknown topic {}, message is dropped",
+                name,
+                msg.topic
+            ),
+        }
+    }
+}
+
+/// Writes output of the runtime process to the desktop log, targeted by replica name
+async fn log_output(stdout: ChildStdout, name: String) {
+    let mut lines = BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        log::info!(target: &name, "{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{forward_input, Runtime};
+    use crossbeam::channel;
+    use grayarea::config::ModuleConfig;
+    use ipc_channel::ipc;
+    use ipc_orchestrator::message::Message;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use tokio::process::Command;
+    use tokio::sync::mpsc;
+
+    fn module(critical: bool) -> ModuleConfig {
+        let yaml = format!(
+            "{{name: failing, kind: processor, module: {{path: failing.wasm}}, critical: {}, supervise: {{backoff_ms: 1}}}}",
+            critical
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    /// Runtime which counts its starts in the file, it fails until started `succeeds_at` times
+    fn runtime(counter: &Path, succeeds_at: u32, critical: bool) -> Runtime {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("n=$(($(cat \"$0\" 2>/dev/null || echo 0) + 1)); echo $n > \"$0\"; [ $n -ge $1 ]")
+            .arg(counter)
+            .arg(succeeds_at.to_string());
+        Runtime::new("failing", cmd, &module(critical))
+    }
+
+    fn starts(counter: &Path) -> u32 {
+        let starts = std::fs::read_to_string(counter).unwrap();
+        std::fs::remove_file(counter).unwrap();
+        starts.trim().parse().unwrap()
+    }
+
+    fn counter(test: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("grayarea_test_{}_{}", test, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn failed_runtime_is_restarted() {
+        let counter = counter("restarted");
+        let runtime = runtime(&counter, 3, false);
+        runtime.supervise(None, HashMap::new()).await.unwrap();
+        assert_eq!(starts(&counter), 3);
+    }
+
+    #[tokio::test]
+    async fn scaled_runtime_is_started_on_scale_up() {
+        let counter = counter("scaled");
+        let runtime = runtime(&counter, 1, false);
+        let (inputs, inputs_rx) = mpsc::unbounded_channel();
+        for _ in 0..2 {
+            inputs.send(channel::bounded(1).1).unwrap();
+        }
+        drop(inputs);
+        let output = HashMap::new();
+        runtime.supervise_scaled(inputs_rx, output).await.unwrap();
+        assert_eq!(starts(&counter), 2);
+    }
+
+    #[test]
+    fn messages_are_buffered_across_restart() {
+        let (input, input_rx) = channel::unbounded();
+        let (bridges, bridges_rx) = channel::unbounded();
+        let forwarder = std::thread::spawn(move || forward_input("f", input_rx, bridges_rx, 3));
+        let msg = |data: u8| Message {
+            topic: "t".to_string(),
+            data: vec![data],
+        };
+        let (tx, rx) = ipc::channel::<Message>().unwrap();
+        bridges.send(tx).unwrap();
+        input.send(msg(0)).unwrap();
+        assert_eq!(rx.recv().unwrap().data, vec![0]);
+        // runtime process fails, messages sent meanwhile wait for the next one
+        drop(rx);
+        for data in 1..4 {
+            input.send(msg(data)).unwrap();
+        }
+        drop(input);
+        let (tx, rx) = ipc::channel::<Message>().unwrap();
+        bridges.send(tx).unwrap();
+        for data in 1..4 {
+            assert_eq!(rx.recv().unwrap().data, vec![data]);
+        }
+        forwarder.join().unwrap();
+        // input is closed, so sender of the runtime is dropped
+        assert!(rx.recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn critical_runtime_is_not_restarted() {
+        let counter = counter("critical");
+        let runtime = runtime(&counter, 3, true);
+        assert!(runtime.supervise(None, HashMap::new()).await.is_err());
+        assert_eq!(starts(&counter), 1);
+    }
+}
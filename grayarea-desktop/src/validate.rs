@@ -0,0 +1,521 @@
+use grayarea::config::{
+    Input, ModuleConfig, ModuleKind, Output, PartitionBy, PipelineConfig, PipelineModule,
+};
+use grayarea::connection::CONNECTION_TOPIC;
+use grayarea::log::LOG_TOPIC;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Topics the host routes functions' log records and connection events to
+const RESERVED_TOPICS: [&str; 2] = [LOG_TOPIC, CONNECTION_TOPIC];
+
+/// Problem found in pipeline configuration
+pub struct Problem {
+    /// Yaml file where problem was found
+    pub file: PathBuf,
+    /// Path to the field in the Yaml file
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.file.display(), self.field, self.message)
+    }
+}
+
+impl Problem {
+    /// Yaml `file` which could not be parsed, problem points to the field error was found at
+    pub fn parse(file: &Path, err: &serde_yaml::Error) -> Self {
+        let message = err.to_string();
+        // error of nested field is prefixed with path to the field, `.` is the document root
+        let mut parts = message.splitn(2, ": ");
+        let (field, message) = match (parts.next(), parts.next()) {
+            (Some(field), Some(message)) if !field.contains(char::is_whitespace) => {
+                (field.to_string(), message.to_string())
+            }
+            _ => (".".to_string(), message),
+        };
+        Problem {
+            file: file.to_path_buf(),
+            field,
+            message,
+        }
+    }
+
+    /// Module config of `index`-th function which could not be loaded,
+    /// problem points to the function in pipeline config
+    pub fn load(
+        pipeline: &Path,
+        index: usize,
+        function: &PipelineModule,
+        err: &anyhow::Error,
+    ) -> Self {
+        let field = match function.config_file() {
+            Some(_) => format!("functions[{}].config", index),
+            None => format!("functions[{}]", index),
+        };
+        Problem {
+            file: pipeline.to_path_buf(),
+            field,
+            message: format!("{:#}", err),
+        }
+    }
+}
+
+/// Validates pipeline topology
+///
+/// `modules` shall contain loaded configs of `config.functions` in the same order.
+/// Returns list of all the problems found, empty list means pipeline is valid.
+pub fn validate(pipeline: &Path, config: &PipelineConfig, modules: &[ModuleConfig]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    // Location of every module config: file and field prefix within the file
+    let locations: Vec<Location> = config
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(i, function)| match function.config_file() {
+            Some(file) => Location {
+                file,
+                prefix: String::new(),
+            },
+            None => Location {
+                file: pipeline,
+                prefix: format!("functions[{}].", i),
+            },
+        })
+        .collect();
+
+    // Function names
+    let mut names = HashMap::new();
+    for (i, function) in config.functions.iter().enumerate() {
+        if let Some(first) = names.insert(function.name.as_str(), i) {
+            problems.push(Problem {
+                file: pipeline.to_path_buf(),
+                field: format!("functions[{}].name", i),
+                message: format!(
+                    "function {} is already defined in functions[{}]",
+                    function.name, first
+                ),
+            });
+        }
+    }
+
+    // Replicas
+    for (i, function) in config.functions.iter().enumerate() {
+        if function.replicas == 0 {
+            problems.push(Problem {
+                file: pipeline.to_path_buf(),
+                field: format!("functions[{}].replicas", i),
+                message: format!("function {} shall have at least 1 replica", function.name),
+            });
+        }
+        match function.autoscale.as_ref() {
+            Some(_) if function.replicas != 1 => problems.push(Problem {
+                file: pipeline.to_path_buf(),
+                field: format!("functions[{}].replicas", i),
+                message: "replicas of autoscaled function are set by autoscale".into(),
+            }),
+            Some(autoscale)
+                if autoscale.min_replicas == 0 || autoscale.min_replicas > autoscale.max_replicas =>
+            {
+                problems.push(Problem {
+                    file: pipeline.to_path_buf(),
+                    field: format!("functions[{}].autoscale", i),
+                    message: format!(
+                        "min_replicas {} shall be from 1 to max_replicas {}",
+                        autoscale.min_replicas, autoscale.max_replicas
+                    ),
+                })
+            }
+            _ => (),
+        }
+        if let Some(PartitionBy::Bytes { start, end }) = function.partition_by {
+            if start >= end {
+                problems.push(Problem {
+                    file: pipeline.to_path_buf(),
+                    field: format!("functions[{}].partition_by", i),
+                    message: format!("bytes range {}..{} is empty", start, end),
+                });
+            }
+        }
+    }
+
+    // Module kinds
+    for (i, (module, location)) in modules.iter().zip(locations.iter()).enumerate() {
+        match module.kind {
+            ModuleKind::Input if module.stream.is_none() => problems.push(
+                location.problem("stream", "input module requires stream configuration".into()),
+            ),
+            // every replica would subscribe to the stream on its own
+            ModuleKind::Input if config.functions[i].max_replicas() > 1 => {
+                let field = match config.functions[i].autoscale {
+                    Some(_) => "autoscale",
+                    None => "replicas",
+                };
+                problems.push(Problem {
+                    file: pipeline.to_path_buf(),
+                    field: format!("functions[{}].{}", i, field),
+                    message: "input function can't have several replicas".into(),
+                })
+            }
+            ModuleKind::Processor if module.input.is_none() && module.output.is_none() => {
+                problems.push(location.problem(
+                    "input",
+                    "processor has neither input nor output configured".into(),
+                ))
+            }
+            _ => (),
+        }
+    }
+
+    // Reserved topics, messages of those are collected by the host
+    for (module, location) in modules.iter().zip(locations.iter()) {
+        let outputs = module.output.iter().flat_map(|Output { topics }| {
+            topics
+                .iter()
+                .enumerate()
+                .map(|(j, topic)| (format!("output.topics[{}]", j), topic))
+        });
+        let inputs = module.input.iter().flat_map(|Input { topic, topics, .. }| {
+            topic
+                .iter()
+                .map(|topic| ("input.topic".to_string(), topic))
+                .chain(
+                    topics
+                        .iter()
+                        .enumerate()
+                        .map(|(j, topic)| (format!("input.topics[{}]", j), topic)),
+                )
+        });
+        let dead_letter = module
+            .dead_letter
+            .iter()
+            .map(|topic| ("dead_letter".to_string(), topic));
+        for (field, topic) in outputs.chain(inputs).chain(dead_letter) {
+            if RESERVED_TOPICS.contains(&topic.as_str()) {
+                problems.push(location.problem(
+                    &field,
+                    format!("topic {} is reserved by the pipeline", topic),
+                ));
+            }
+        }
+    }
+
+    // Topics: index of consumers and producers by topic name
+    let mut consumers: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut producers: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, module) in modules.iter().enumerate() {
+        if let Some(input) = module.input.as_ref() {
+            for topic in input.all_topics() {
+                consumers.entry(topic.as_str()).or_default().push(i);
+            }
+        }
+        if let Some(Output { topics }) = module.output.as_ref() {
+            for topic in topics.iter() {
+                producers.entry(topic.as_str()).or_default().push(i);
+            }
+        }
+        // dead letter topic might be consumed, otherwise messages are stored by the host
+        if let Some(topic) = module.dead_letter.as_ref() {
+            producers.entry(topic.as_str()).or_default().push(i);
+        }
+    }
+    for (module, location) in modules.iter().zip(locations.iter()) {
+        if let Some(Output { topics }) = module.output.as_ref() {
+            for (j, topic) in topics.iter().enumerate() {
+                if !consumers.contains_key(topic.as_str()) {
+                    problems.push(location.problem(
+                        &format!("output.topics[{}]", j),
+                        format!("topic {} is not consumed by any function", topic),
+                    ));
+                }
+            }
+        }
+        if let Some(Input { topic, topics, .. }) = module.input.as_ref() {
+            if topic.is_none() && topics.is_empty() {
+                problems.push(location.problem(
+                    "input",
+                    "input has neither topic nor topics configured".into(),
+                ));
+            }
+            let fields = topic
+                .iter()
+                .map(|topic| ("input.topic".to_string(), topic))
+                .chain(
+                    topics
+                        .iter()
+                        .enumerate()
+                        .map(|(j, topic)| (format!("input.topics[{}]", j), topic)),
+                );
+            for (field, topic) in fields {
+                if !producers.contains_key(topic.as_str()) {
+                    problems.push(location.problem(
+                        &field,
+                        format!("topic {} is not produced by any function", topic),
+                    ));
+                }
+            }
+        }
+    }
+
+    // Cycles in the topic graph
+    for (from, to, j) in find_cycles(modules, &consumers) {
+        let topics = &modules[from].output.as_ref().unwrap().topics;
+        problems.push(locations[from].problem(
+            &format!("output.topics[{}]", j),
+            format!(
+                "topic {} closes a cycle through function {}",
+                topics[j], modules[to].name
+            ),
+        ));
+    }
+
+    problems
+}
+
+struct Location<'a> {
+    file: &'a Path,
+    prefix: String,
+}
+
+impl<'a> Location<'a> {
+    fn problem(&self, field: &str, message: String) -> Problem {
+        Problem {
+            file: self.file.to_path_buf(),
+            field: format!("{}{}", self.prefix, field),
+            message,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    New,
+    InProgress,
+    Done,
+}
+
+/// Depth first search over functions connected via topics.
+/// Returns back edges as (producer, consumer, index of producer's output topic)
+fn find_cycles(
+    modules: &[ModuleConfig],
+    consumers: &HashMap<&str, Vec<usize>>,
+) -> Vec<(usize, usize, usize)> {
+    let edges: Vec<Vec<(usize, usize)>> = modules
+        .iter()
+        .map(|module| {
+            module
+                .output
+                .as_ref()
+                .map(|Output { topics }| {
+                    topics
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(j, topic)| consumers.get(topic.as_str()).map(|c| (j, c)))
+                        .flat_map(|(j, c)| c.iter().map(move |to| (*to, j)))
+                        .collect()
+                })
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let mut state = vec![Visit::New; modules.len()];
+    let mut back_edges = Vec::new();
+    for root in 0..modules.len() {
+        if state[root] != Visit::New {
+            continue;
+        }
+        // stack of (function, index of next edge to follow)
+        let mut stack = vec![(root, 0)];
+        state[root] = Visit::InProgress;
+        while let Some((from, next)) = stack.pop() {
+            if let Some(&(to, j)) = edges[from].get(next) {
+                stack.push((from, next + 1));
+                match state[to] {
+                    Visit::New => {
+                        state[to] = Visit::InProgress;
+                        stack.push((to, 0));
+                    }
+                    Visit::InProgress => back_edges.push((from, to, j)),
+                    Visit::Done => (),
+                }
+            } else {
+                state[from] = Visit::Done;
+            }
+        }
+    }
+    back_edges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, Problem};
+    use grayarea::config::{ModuleConfig, PipelineConfig};
+    use std::path::Path;
+
+    fn pipeline(names: &[&str]) -> PipelineConfig {
+        let functions: Vec<String> = names
+            .iter()
+            .map(|name| format!("  - name: {0}\n    config: {0}.yml\n", name))
+            .collect();
+        serde_yaml::from_str(&format!("functions:\n{}", functions.concat())).unwrap()
+    }
+
+    fn module(yaml: &str) -> ModuleConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn fields(pipeline: &PipelineConfig, modules: &[ModuleConfig]) -> Vec<String> {
+        validate(Path::new("functions.yml"), pipeline, modules)
+            .into_iter()
+            .map(|p| format!("{}: {}", p.file.display(), p.field))
+            .collect()
+    }
+
+    #[test]
+    fn valid_pipeline() {
+        let modules = vec![
+            module("{name: a, kind: processor, module: {path: a.wasm}, output: {topics: [t]}}"),
+            module("{name: b, kind: processor, module: {path: b.wasm}, input: {topic: t}}"),
+        ];
+        assert!(fields(&pipeline(&["a", "b"]), &modules).is_empty());
+    }
+
+    #[test]
+    fn dangling_topics_and_duplicates() {
+        let modules = vec![
+            module("{name: a, kind: input, module: {path: a.wasm}, output: {topics: [t, u]}}"),
+            module("{name: a, kind: processor, module: {path: b.wasm}, input: {topic: v}}"),
+            module("{name: c, kind: processor, module: {path: c.wasm}}"),
+        ];
+        assert_eq!(
+            fields(&pipeline(&["a", "a", "c"]), &modules),
+            vec![
+                "functions.yml: functions[1].name",
+                "a.yml: stream",
+                "c.yml: input",
+                "a.yml: output.topics[0]",
+                "a.yml: output.topics[1]",
+                "a.yml: input.topic",
+            ]
+        );
+    }
+
+    #[test]
+    fn inline_module_location() {
+        let pipeline: PipelineConfig = serde_yaml::from_str(
+            "functions:\n  - name: a\n    config: a.yml\n  - name: b\n    kind: processor\n    module: {path: b.wasm}\n    input: {topic: u}\n",
+        )
+        .unwrap();
+        let modules = vec![
+            module("{name: a, kind: processor, module: {path: a.wasm}, output: {topics: [t]}}"),
+            module("{name: b, kind: processor, module: {path: b.wasm}, input: {topic: u}}"),
+        ];
+        assert_eq!(
+            fields(&pipeline, &modules),
+            vec![
+                "a.yml: output.topics[0]",
+                "functions.yml: functions[1].input.topic"
+            ]
+        );
+    }
+
+    #[test]
+    fn multiple_input_topics() {
+        let modules = vec![
+            module("{name: a, kind: processor, module: {path: a.wasm}, output: {topics: [t, u]}}"),
+            module("{name: b, kind: processor, module: {path: b.wasm}, input: {topics: [t, u, v]}}"),
+            module("{name: c, kind: processor, module: {path: c.wasm}, input: {}}"),
+        ];
+        assert_eq!(
+            fields(&pipeline(&["a", "b", "c"]), &modules),
+            vec!["b.yml: input.topics[2]", "c.yml: input"]
+        );
+    }
+
+    #[test]
+    fn topic_cycle() {
+        let modules = vec![
+            module("{name: a, kind: processor, module: {path: a.wasm}, input: {topic: t2}, output: {topics: [t1]}}"),
+            module("{name: b, kind: processor, module: {path: b.wasm}, input: {topic: t1}, output: {topics: [t2]}}"),
+        ];
+        assert_eq!(
+            fields(&pipeline(&["a", "b"]), &modules),
+            vec!["b.yml: output.topics[0]"]
+        );
+    }
+
+    #[test]
+    fn input_replicas_and_partition_range() {
+        let pipeline: PipelineConfig = serde_yaml::from_str(
+            "functions:\n  - name: a\n    config: a.yml\n    replicas: 2\n  - name: b\n    config: b.yml\n    replicas: 2\n    partition_by: {bytes: {start: 4, end: 4}}\n",
+        )
+        .unwrap();
+        let modules = vec![
+            module("{name: a, kind: input, module: {path: a.wasm}, stream: {websocket: {url: \"wss://host\"}}, output: {topics: [t]}}"),
+            module("{name: b, kind: processor, module: {path: b.wasm}, input: {topic: t}}"),
+        ];
+        assert_eq!(
+            fields(&pipeline, &modules),
+            vec![
+                "functions.yml: functions[1].partition_by",
+                "functions.yml: functions[0].replicas",
+            ]
+        );
+    }
+
+    #[test]
+    fn reserved_topics() {
+        let modules = vec![
+            module("{name: a, kind: processor, module: {path: a.wasm}, output: {topics: [grayarea.log]}, dead_letter: grayarea.connection}"),
+            module("{name: b, kind: processor, module: {path: b.wasm}, input: {topic: grayarea.log}}"),
+        ];
+        assert_eq!(
+            fields(&pipeline(&["a", "b"]), &modules),
+            vec![
+                "a.yml: output.topics[0]",
+                "a.yml: dead_letter",
+                "b.yml: input.topic"
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_pipeline() {
+        let parse = |yaml: &str| {
+            let err = serde_yaml::from_str::<PipelineConfig>(yaml).err().unwrap();
+            let problem = Problem::parse(Path::new("functions.yml"), &err);
+            format!("{}: {}", problem.file.display(), problem.field)
+        };
+        assert_eq!(
+            parse("functions:\n  - name: a\n    config: a.yml\n    replicas: many\n"),
+            "functions.yml: functions[0].replicas"
+        );
+        assert_eq!(parse("pipeline: []\n"), "functions.yml: .");
+    }
+
+    #[test]
+    fn load_problem() {
+        let pipeline: PipelineConfig = serde_yaml::from_str(
+            "functions:\n  - name: a\n    config: missing.yml\n  - name: b\n    kind: processor\n",
+        )
+        .unwrap();
+        let err = anyhow::anyhow!("missing field `module`");
+        let problems: Vec<String> = pipeline
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(i, function)| Problem::load(Path::new("functions.yml"), i, function, &err))
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "functions.yml: functions[0].config: missing field `module`",
+                "functions.yml: functions[1]: missing field `module`",
+            ]
+        );
+    }
+}
@@ -1,2 +1,2 @@
 mod options;
-pub use options::Opt;
+pub use options::{read_config, Command, Opt};
@@ -1,185 +1,631 @@
 use anyhow::{anyhow, Result};
 use crossbeam::channel;
-use futures::future::{try_join_all, TryFutureExt};
-use grayarea::{config, WasmHandler, WasmTopicInstance, WasmWSInstance, WebSocket};
-use grayarea_runtime::Opt;
+use futures::future::TryFutureExt;
+use grayarea::config::ModuleConfig;
+use grayarea::connection::{ConnectionEvent, ConnectionState, CONNECTION_TOPIC};
+use grayarea::header::{self, ContentType, Headers};
+use grayarea::log::{LogRecord, LOG_TOPIC};
+use grayarea::{
+    config, Counters, Inbound, Limit, LimitError, ModuleCache, SessionEvent, Settings, WasmError,
+    WasmHandler, WasmTopicInstance, WasmWSInstance, Watchdog, WebSocket,
+};
+use grayarea_runtime::{read_config, Command, Opt};
 use ipc_orchestrator::{message::Message, Receiver, Sender};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use structopt::StructOpt;
 use tokio::task::spawn_blocking;
 use tungstenite::protocol::Message as WSMessage;
 
 type Handle = tokio::task::JoinHandle<Result<()>>;
 
-async fn ws_processor(tx: Sender, ws: WebSocket, topic: String) -> anyhow::Result<()> {
-    while let Some(msg) = ws.read().await {
-        let topic = topic.clone();
-        match msg {
-            // Send message as &[u8] to wasm module
-            Ok(WSMessage::Text(t)) => tx.send(Message {
-                topic,
-                data: t.into_bytes(),
-            })?, // this might block - think again if we shall block here
-            Ok(WSMessage::Binary(data)) => tx.send(Message { topic, data })?, // this might block - think again if we shall block here
+/// Interval of publishing counters of processed messages to the pipeline log
+const COUNTERS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Forwards messages of the stream to the pipeline, connection is established again
+/// whenever it's lost until reconnect policy of the stream is exhausted.
+/// Handshaker hooks are notified of connection events and messages of the stream.
+/// Messages are published to `topic` with `source` replica, or passed to the module
+/// when it transforms the stream.
+async fn ws_processor(
+    tx: Sender,
+    ws: WebSocket,
+    handshaker: &WasmWSInstance,
+    config: &ModuleConfig,
+    stream: &config::WebSocketConfig,
+    topic: Option<String>,
+    source: &str,
+) -> anyhow::Result<()> {
+    let function = config.name.as_str();
+    let topic = topic.as_deref();
+    let mut seq = 0;
+    // whether module handles frames is known once it is instantiated
+    handshaker.instantiated().await;
+    loop {
+        let (attempt, url) = ws
+            .reconnect(stream, |attempt, url, err| {
+                let url = config.secrets.mask(url.as_str());
+                let error = err.to_string();
+                let event = ConnectionEvent::new(
+                    function,
+                    &url,
+                    ConnectionState::Failed { attempt, error },
+                );
+                if let Err(err) = publish_event(&tx, event) {
+                    log::error!(
+                        "function {} failed to publish connection event: {}",
+                        function,
+                        err
+                    );
+                }
+            })
+            .await?;
+        let url = config.secrets.mask(url.as_str());
+        log::info!("function {} connected to {}", function, url);
+        handshaker
+            .notify(SessionEvent::Connected(url.clone()))
+            .await;
+        publish_event(
+            &tx,
+            ConnectionEvent::new(function, &url, ConnectionState::Connected { attempt }),
+        )?;
+        let mut watchdog = Watchdog::new(&stream.heartbeat);
+        let reason = read_stream(
+            &tx,
+            &ws,
+            &mut watchdog,
+            handshaker,
+            function,
+            source,
+            topic,
+            &url,
+            &mut seq,
+        )
+        .await?;
+        ws.clean().await;
+        handshaker
+            .notify(SessionEvent::Disconnected(reason.clone()))
+            .await;
+        publish_event(
+            &tx,
+            ConnectionEvent::new(function, &url, ConnectionState::Disconnected { reason }),
+        )?;
+    }
+}
+
+/// Forwards messages of the stream to the pipeline until connection is lost,
+/// returns reason of disconnection. Messages passed to the module have url as their topic.
+/// Connection which trips heartbeat check is considered lost, alert is published then
+#[allow(clippy::too_many_arguments)]
+async fn read_stream(
+    tx: &Sender,
+    ws: &WebSocket,
+    watchdog: &mut Watchdog,
+    handshaker: &WasmWSInstance,
+    function: &str,
+    source: &str,
+    topic: Option<&str>,
+    url: &str,
+    seq: &mut u64,
+) -> anyhow::Result<String> {
+    loop {
+        let msg = match watchdog.read(ws).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return Ok("stream ended".to_string()),
+            Err(alert) => {
+                let reason = alert.to_string();
+                let state = ConnectionState::Alert {
+                    reason: reason.clone(),
+                };
+                publish_event(tx, ConnectionEvent::new(function, url, state))?;
+                return Ok(reason);
+            }
+        };
+        let (content_type, data) = match msg {
+            Ok(WSMessage::Text(t)) => (ContentType::Text, t.into_bytes()),
+            Ok(WSMessage::Binary(data)) => (ContentType::Binary, data),
             // Reply on ping from ws server
-            Ok(WSMessage::Ping(v)) => ws.pong(v).await?,
-            Ok(WSMessage::Pong(_)) => (),
-            // Following is most likely websocket connection error
-            // TODO: shall we restart connection on error?
-            //   If that is the case perhaps processor should be part of websocket logic?
-            Ok(WSMessage::Close(_)) => {
-                ws.clean().await;
-                return Err(anyhow!("Connection closed"));
+            Ok(WSMessage::Ping(v)) => {
+                if let Err(err) = ws.pong(v).await {
+                    return Ok(err.to_string());
+                }
+                continue;
             }
-            Err(err) => return Err(err.into()),
+            Ok(WSMessage::Pong(_)) => continue,
+            Ok(WSMessage::Close(Some(frame))) => return Ok(format!("closed by server: {}", frame)),
+            Ok(WSMessage::Close(None)) => return Ok("closed by server".to_string()),
+            Err(err) => return Ok(err.to_string()),
+        };
+        if handshaker.handles_messages() {
+            handshaker.notify(SessionEvent::Message(data.clone())).await;
+        }
+        let headers = Headers::new(source, topic.unwrap_or(url), *seq, content_type);
+        *seq += 1;
+        let envelope = header::encode(&headers, &data)?;
+        match topic {
+            // Send message with headers to the pipeline
+            Some(topic) => tx.send(Message {
+                topic: topic.to_string(),
+                data: envelope,
+            })?, // this might block - think again if we shall block here
+            // module routes message to output topics on its own
+            None => handshaker.notify(SessionEvent::Envelope(envelope)).await,
         }
     }
+}
+
+/// Publishes lifecycle event of the stream to the pipeline host
+fn publish_event(tx: &Sender, event: ConnectionEvent) -> anyhow::Result<()> {
+    tx.send(Message {
+        topic: CONNECTION_TOPIC.to_string(),
+        data: event.to_bytes()?,
+    })?;
     Ok(())
 }
 
+/// Forwards messages from the pipeline to the function until pipeline closes the channel,
+/// function finishes once it processed the buffered ones then
 async fn msg_processor(tx: channel::Sender<Vec<u8>>, rx: Receiver) -> anyhow::Result<()> {
-    let res = spawn_blocking(move || loop {
-        let msg = rx.recv()?;
+    spawn_blocking(move || loop {
+        let msg = match rx.recv() {
+            Ok(msg) => msg,
+            Err(err) => match *err {
+                ipc_channel::ErrorKind::Io(ref err)
+                    if err.kind() == std::io::ErrorKind::ConnectionReset =>
+                {
+                    return Ok(())
+                }
+                _ => return Err(err.into()),
+            },
+        };
         tx.send(msg.data)?;
     })
-    .await;
-    dbg!(&res);
-    res?
+    .await?
 }
 
 async fn out_msg_processor(tx: Sender, rx: channel::Receiver<Message>) -> anyhow::Result<()> {
-    let res = spawn_blocking(move || loop {
-        let msg = rx.recv()?;
-        tx.send(msg)?;
+    spawn_blocking(move || {
+        for msg in rx.iter() {
+            tx.send(msg)?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Forwards log records of the module to the pipeline host,
+/// writes them to the runtime log when running without IPC
+async fn log_processor(tx: Option<Sender>, rx: channel::Receiver<LogRecord>) -> anyhow::Result<()> {
+    spawn_blocking(move || {
+        for record in rx.iter() {
+            match tx.as_ref() {
+                Some(tx) => tx.send(Message {
+                    topic: LOG_TOPIC.to_string(),
+                    data: record.to_bytes()?,
+                })?,
+                None => log::log!(target: &record.function, record.level, "{}", record),
+            }
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Forwards messages module failed to process to the dead letter topic,
+/// writes their headers to the runtime log when running without IPC
+async fn dead_letter_processor(
+    tx: Option<Sender>,
+    topic: String,
+    rx: channel::Receiver<Vec<u8>>,
+) -> anyhow::Result<()> {
+    spawn_blocking(move || {
+        for data in rx.iter() {
+            match tx.as_ref() {
+                Some(tx) => tx.send(Message {
+                    topic: topic.clone(),
+                    data,
+                })?,
+                None => log::warn!("dead letter {:?}", header::decode(&data)?.0),
+            }
+        }
+        Ok(())
     })
-    .await;
-    dbg!(&res);
+    .await?
+}
+
+/// Awaits function reporting counters of processed messages once it stops
+async fn report_counters(name: String, handle: Handle, counters: Arc<Counters>) -> Result<()> {
+    let res = handle.await;
+    log::info!("function {} messages: {}", name, counters);
     res?
 }
 
-// spawns worker of type input stream
-async fn spawn_input(opt: Opt, config: config::ModuleConfig) -> anyhow::Result<Vec<Handle>> {
-    let mut handles = Vec::new();
+/// Publishes counters of processed messages to the pipeline log every `COUNTERS_INTERVAL`
+/// when those changed, writes them to the runtime log when running without IPC
+async fn publish_counters(
+    tx: Option<Sender>,
+    function: String,
+    counters: Arc<Counters>,
+) -> Result<()> {
+    let mut interval = tokio::time::interval(COUNTERS_INTERVAL);
+    let mut last = format!("messages: {}", counters);
+    loop {
+        interval.tick().await;
+        let text = format!("messages: {}", counters);
+        if text == last {
+            continue;
+        }
+        match tx.as_ref() {
+            Some(tx) => {
+                let record = LogRecord {
+                    function: function.clone(),
+                    level: log::Level::Info,
+                    message: None,
+                    timestamp: SystemTime::now(),
+                    text: text.clone(),
+                };
+                tx.send(Message {
+                    topic: LOG_TOPIC.to_string(),
+                    data: record.to_bytes()?,
+                })?
+            }
+            None => log::info!("function {} {}", function, text),
+        }
+        last = text;
+    }
+}
+
+/// Connection of the function to the pipeline, kept while function is restarted
+struct Pipeline {
+    /// Sender of messages to the pipeline, `None` when running without IPC
+    tx: Option<Sender>,
+    /// Name of the function replica, source of messages it produces
+    replica: String,
+    /// Messages from the pipeline, buffered while function is down
+    inbound: channel::Receiver<Vec<u8>>,
+    /// Keeps inbound channel open when running without IPC
+    _inbound_tx: Option<channel::Sender<Vec<u8>>>,
+}
+
+impl Pipeline {
+    /// Connects to the pipeline via IPC when available,
+    /// returns handle of the processor receiving messages from the pipeline
+    async fn connect(opt: &Opt, config: &ModuleConfig) -> Result<(Self, Option<Handle>)> {
+        let (inbound_tx, inbound) = channel::bounded::<Vec<u8>>(config.supervise.buffer);
+        let replica = opt.replica(config);
+        if opt.has_ipc() {
+            let (stx, srx) = opt.ipc_channel().await?.split()?;
+            let handle = tokio::spawn(
+                msg_processor(inbound_tx, srx).map_err(|err| err.context("Communication failure")),
+            );
+            let pipeline = Pipeline {
+                tx: Some(stx),
+                replica,
+                inbound,
+                _inbound_tx: None,
+            };
+            Ok((pipeline, Some(handle)))
+        } else {
+            let pipeline = Pipeline {
+                tx: None,
+                replica,
+                inbound,
+                _inbound_tx: Some(inbound_tx),
+            };
+            Ok((pipeline, None))
+        }
+    }
+
+    /// Forwards log records and dead letters of the function to the pipeline
+    fn forward(
+        &self,
+        config: &ModuleConfig,
+        logs: channel::Receiver<LogRecord>,
+        dead_letters: Option<channel::Receiver<Vec<u8>>>,
+    ) {
+        detach(&config.name, log_processor(self.tx.clone(), logs));
+        if let Some((topic, rx)) = config.dead_letter.clone().zip(dead_letters) {
+            detach(
+                &config.name,
+                dead_letter_processor(self.tx.clone(), topic, rx),
+            );
+        }
+    }
+}
+
+/// Spawns processor of the function reporting its failure
+fn detach(
+    function: &str,
+    processor: impl std::future::Future<Output = Result<()>> + Send + 'static,
+) {
+    let function = function.to_string();
+    tokio::spawn(async move {
+        if let Err(err) = processor.await {
+            log::error!("function {} processor failed: {}", function, err);
+        }
+    });
+}
+
+/// Runs function restarting it according to its restart policy,
+/// failure which is not going to be recovered by this runtime is returned
+async fn supervise(config: ModuleConfig, pipeline: Pipeline) -> Result<()> {
     let wasm_bytes = config.load_wasm_bytes().await?;
-    match &config.stream {
-        Some(config::StreamOneOf::WebSocket(config::WebSocketConfig { url })) => {
-            let wasm_handler = WasmWSInstance::spawn(wasm_bytes, config.args_as_bytes());
-
-            // Connect to pipeline via IPC
-            let (stx, _) = opt.ipc_channel().await?.split()?;
-
-            let ws = WebSocket::default();
-            ws.connect(url.clone()).await?;
-            // TODO - structured logging to stderr
-            println!("Connected to {}", &url);
-            // Spawn websocket messages processor
-            //let topic = config.topics()?.remove(0);
-            let topic = config.output.as_ref()
-                .ok_or_else(|| anyhow!("module {} does not have output topics configured", config.name))?
-                .topics[0].clone();
-            let ws_handle = tokio::spawn(ws_processor(stx, ws.clone(), topic));
-            handles.push(ws_handle);
-
-            // Spawn wasm message processor
-            let wasm_msgs_handle =
-                tokio::spawn(async move { ws.set_handshaker(&wasm_handler).await });
-            handles.push(wasm_msgs_handle);
-        }
-        None => panic!(
-            "Stream configuration was not provided, it's required for *input* type of instance!"
-        ),
+    // counters are kept between restarts of the module
+    let counters = Arc::new(Counters::default());
+    detach(
+        &config.name,
+        publish_counters(pipeline.tx.clone(), config.name.clone(), counters.clone()),
+    );
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        let res = run(&config, wasm_bytes.clone(), &pipeline, &counters).await;
+        // function which ran for a while starts over from the initial backoff
+        if started.elapsed() > config.restart.max_backoff() {
+            restarts = 0;
+        }
+        let backoff = config.restart.backoff(restarts);
+        match res {
+            Ok(()) if !config.restart.should_restart(false, restarts) => return Ok(()),
+            Ok(()) => log::info!(
+                "function {} finished, restarting in {:?}",
+                config.name,
+                backoff
+            ),
+            Err(err) if !restartable(&err) || !config.restart.should_restart(true, restarts) => {
+                return Err(err)
+            }
+            Err(err) => log::warn!(
+                "function {} failed: {}, restarting in {:?}",
+                config.name,
+                err,
+                backoff
+            ),
+        }
+        tokio::time::delay_for(backoff).await;
+        restarts += 1;
+    }
+}
+
+/// Thread stuck in WASM code over time limit can't be stopped, so function is not restarted
+/// by this runtime, its process is started again by the pipeline instead
+fn restartable(err: &anyhow::Error) -> bool {
+    !matches!(
+        err.downcast_ref::<WasmError>(),
+        Some(WasmError::Limit(LimitError {
+            limit: Limit::Timeout { .. },
+            ..
+        }))
+    )
+}
+
+/// Runs function once, returns when it finishes or fails
+async fn run(
+    config: &ModuleConfig,
+    wasm_bytes: Vec<u8>,
+    pipeline: &Pipeline,
+    counters: &Arc<Counters>,
+) -> Result<()> {
+    let settings = Settings {
+        source: pipeline.replica.clone(),
+        counters: counters.clone(),
+        ..Settings::new(config)?
     };
-    Ok(handles)
+    match config.kind {
+        config::ModuleKind::Input => run_input(config, settings, wasm_bytes, pipeline).await,
+        config::ModuleKind::Processor if config.output.is_some() => {
+            run_with_output(config, settings, wasm_bytes, pipeline).await
+        }
+        config::ModuleKind::Processor => {
+            run_no_output(config, settings, wasm_bytes, pipeline).await
+        }
+    }
 }
 
-// spawns worker of type processor without specified outputs
-async fn spawn_no_output(opt: Opt, config: config::ModuleConfig) -> anyhow::Result<Vec<Handle>> {
-    let mut handles = Vec::new();
-    let wasm_bytes = config.load_wasm_bytes().await?;
-    let args = config.args_as_bytes();
-    let wasm_handler = WasmHandler::spawn(wasm_bytes, args, None, true);
-
-    if opt.has_ipc() {
-        let (_, srx) = opt.ipc_channel().await?.split()?;
-
-        // spawn IPC messages processor
-        let tx = wasm_handler
-            .clone_sender()
-            .expect("Receiver of messages not started");
-        let ws_handle = tokio::spawn(
-            msg_processor(tx, srx)
-                .or_else(|err| async move { panic!("Communication failure: {}", err) }),
-        );
-        handles.push(ws_handle);
+// runs worker of type input stream
+async fn run_input(
+    config: &ModuleConfig,
+    settings: Settings,
+    wasm_bytes: Vec<u8>,
+    pipeline: &Pipeline,
+) -> Result<()> {
+    match &config.stream {
+        Some(config::StreamOneOf::WebSocket(stream)) => {
+            let stx = pipeline
+                .tx
+                .clone()
+                .ok_or_else(|| anyhow!("stream of {} requires IPC", config.name))?;
+            let topics = config.output.as_ref().map(|output| output.topics.clone());
+            let topic = topics.as_ref().and_then(|topics| topics.first().cloned());
+            let topic = topic.ok_or_else(|| {
+                anyhow!(
+                    "module {} does not have output topics configured",
+                    config.name
+                )
+            })?;
+            // Module transforming the stream sends messages to output topics on its own
+            let (topics, topic) = if stream.transform {
+                (topics, None)
+            } else {
+                (None, Some(topic))
+            };
+            let (wasm_handler, mut handle) = WasmWSInstance::spawn(wasm_bytes, settings, topics);
+            let logs = wasm_handler.clone_log_receiver();
+            pipeline.forward(config, logs, wasm_handler.clone_dead_letter_receiver());
+            if let Some(rx) = wasm_handler.clone_topic_receiver() {
+                detach(&config.name, out_msg_processor(stx.clone(), rx));
+            }
+
+            // Handshaker messages are sent once stream is connected and again on every reconnect
+            let ws = WebSocket::with_secrets(config.secrets.clone());
+            let source = pipeline.replica.as_str();
+            // Function fails as soon as either websocket or wasm message processor fails,
+            // failure of the module, e.g. over time limit, comes back via its handle
+            tokio::select! {
+                res = ws_processor(
+                    stx, ws.clone(), &wasm_handler, config, stream, topic, source
+                ) => res,
+                res = ws.set_handshaker(&wasm_handler) => match res {
+                    Ok(()) => handle.await?,
+                    Err(err) => Err(err),
+                },
+                res = &mut handle => res?,
+            }
+        }
+        None => Err(anyhow!(
+            "stream configuration of {} is required for input function",
+            config.name
+        )),
     }
+}
 
-    handles.push(wasm_handler.into());
+// runs worker of type processor without specified outputs
+async fn run_no_output(
+    config: &ModuleConfig,
+    settings: Settings,
+    wasm_bytes: Vec<u8>,
+    pipeline: &Pipeline,
+) -> Result<()> {
+    let inbound = Some(Inbound::Messages(pipeline.inbound.clone()));
+    let wasm_handler = WasmHandler::spawn(wasm_bytes, settings, None, inbound);
+    let logs = wasm_handler.clone_log_receiver();
+    pipeline.forward(config, logs, wasm_handler.clone_dead_letter_receiver());
 
-    Ok(handles)
+    let counters = wasm_handler.counters();
+    report_counters(config.name.clone(), wasm_handler.into(), counters).await
 }
 
-// spawns worker of type processor with specifid outputs
-async fn spawn_with_output(opt: Opt, config: config::ModuleConfig) -> anyhow::Result<Vec<Handle>> {
-    let mut handles = Vec::new();
-    let wasm_bytes = config.load_wasm_bytes().await?;
-    let args = config.args_as_bytes();
+// runs worker of type processor with specifid outputs
+async fn run_with_output(
+    config: &ModuleConfig,
+    settings: Settings,
+    wasm_bytes: Vec<u8>,
+    pipeline: &Pipeline,
+) -> Result<()> {
     let topics = config.topics()?;
-    let wasm_handler = WasmTopicInstance::spawn(wasm_bytes, args, topics);
-
-    if opt.has_ipc() {
-        let (stx, srx) = opt.ipc_channel().await?.split()?;
-
-        // spawn IPC messages processor
-        let tx = wasm_handler
-            .clone_sender()
-            .expect("Receiver of messages not started");
-        // TODO: is there a way to get rid of this spawn?
-        let ws_handle = tokio::spawn(
-            msg_processor(tx, srx)
-                .or_else(|err| async move { panic!("Communication failure: {}", err) }),
-        );
-        handles.push(ws_handle);
+    let inbound = pipeline.inbound.clone();
+    let wasm_handler = WasmTopicInstance::spawn(wasm_bytes, settings, topics, inbound);
+    let logs = wasm_handler.clone_log_receiver();
+    pipeline.forward(config, logs, wasm_handler.clone_dead_letter_receiver());
 
-        let ws_handle = tokio::spawn(
-            out_msg_processor(stx, wasm_handler.clone_receiver())
-                .or_else(|err| async move { panic!("Communication failure: {}", err) }),
+    if let Some(stx) = pipeline.tx.clone() {
+        detach(
+            &config.name,
+            out_msg_processor(stx, wasm_handler.clone_receiver()),
         );
-        handles.push(ws_handle);
     }
 
-    handles.push(wasm_handler.into());
+    let counters = wasm_handler.counters();
+    report_counters(config.name.clone(), wasm_handler.into(), counters).await
+}
 
-    Ok(handles)
+// compiles module into compiled modules cache without running it
+async fn compile(path: &Path) -> anyhow::Result<()> {
+    let config = read_config(path).await?;
+    if config.limits.metering.is_some() {
+        return Err(anyhow!(
+            "module of {} has metering limit and is compiled on start",
+            config.name
+        ));
+    }
+    let wasm_bytes = config.load_wasm_bytes().await?;
+    let (dir, status) = spawn_blocking(move || {
+        let mut cache = ModuleCache::open()?;
+        let (_, status) = cache.load_or_compile(&wasm_bytes)?;
+        Ok::<_, anyhow::Error>((cache.dir().to_path_buf(), status))
+    })
+    .await??;
+    println!("Module of {} {} in {:?}", config.name, status, dir);
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    init_log_engine();
     let opt = Opt::from_args();
+    if let Some(Command::Compile { config }) = opt.command.as_ref() {
+        return compile(config).await;
+    }
     // Load the plugin data
     let config = opt.load_config().await?;
+    let name = config.name.clone();
 
-    // Spawn wasm module in separate thread
-    // also receive msgs bridge to wasm module
-    let handles = match config.kind {
-        config::ModuleKind::Input => spawn_input(opt, config).await?,
-        config::ModuleKind::Processor if config.output.is_some() => {
-            spawn_with_output(opt, config).await?
-        }
-        config::ModuleKind::Processor => spawn_no_output(opt, config).await?,
-    };
+    // Connection to the pipeline belongs to the runtime, so that function keeps its routes
+    // while it is restarted by supervisor
+    let (pipeline, receiver) = Pipeline::connect(&opt, &config).await?;
 
-    // Await them all in parallel
-    // TODO: Implement graceful cancellation and restart on Err
+    // Function is restarted on failure according to its restart policy,
+    // any error coming back here means function is not going to recover in this runtime
+    // TODO: Implement graceful cancellation
     // until then following code will just force exit killing all running futures
     // https://github.com/Matthias247/futures-intrusive/blob/master/examples/cancellation.rs
-
-    let res = try_join_all(handles).await;
-    dbg!(&res);
+    let res = match receiver {
+        Some(receiver) => {
+            let supervisor = supervise(config, pipeline);
+            tokio::pin!(supervisor);
+            tokio::select! {
+                res = &mut supervisor => res,
+                res = receiver => match res.map_err(Into::into).and_then(|res| res) {
+                    // pipeline closed the channel, e.g. replica was scaled down
+                    Ok(()) => {
+                        log::info!("function {} input is closed, finishing", name);
+                        supervisor.await
+                    }
+                    Err(err) => Err(err),
+                },
+            }
+        }
+        None => supervise(config, pipeline).await,
+    };
     // TODO -- rustc bug??: unoptimized build is exiting, release is hanging if without following check:
     match res {
         Err(err) => {
-            dbg!(err);
+            log::error!("function {} failed: {:#}", name, err);
             std::process::exit(1)
         }
         _ => Ok(()),
     }
 }
+
+/// Runtime logs at info level unless `RUST_LOG` says otherwise,
+/// e.g. `RUST_LOG=grayarea::websocket=debug` prints messages sent by handshaker
+fn init_log_engine() {
+    let mut builder = pretty_env_logger::formatted_timed_builder();
+    builder
+        .filter_level(log::LevelFilter::Info)
+        .default_format_module_path(true);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::restartable;
+    use grayarea::{Limit, LimitError, WasmError};
+    use std::time::Duration;
+
+    #[test]
+    fn timeout_is_not_restartable() {
+        let limit = |limit| {
+            anyhow::Error::from(WasmError::Limit(LimitError {
+                function: "f".to_string(),
+                message: Some(1),
+                limit,
+            }))
+        };
+        let (elapsed, max) = (Duration::from_secs(2), Duration::from_secs(1));
+        assert!(!restartable(&limit(Limit::Timeout { elapsed, max })));
+        assert!(restartable(&limit(Limit::Memory { pages: 5, max: 4 })));
+        let trap = WasmError::Trap {
+            function: "f".to_string(),
+            message: None,
+            export: "_start".to_string(),
+            error: "unreachable".to_string(),
+        };
+        assert!(restartable(&trap.into()));
+        assert!(restartable(&anyhow::anyhow!("stream ended")));
+    }
+}
@@ -1,8 +1,8 @@
 use anyhow::{anyhow, Context, Result};
-use grayarea::config::ModuleConfig;
+use grayarea::config::{ModuleConfig, MODULE_CONFIG_ENV_VAR, REPLICA_ENV_VAR};
 use ipc_channel::ipc::IpcSender;
 use ipc_orchestrator::{Channel, connect_ipc_server};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use tokio::fs::read;
 use tokio::task::spawn_blocking;
@@ -13,18 +13,38 @@ use tokio::task::spawn_blocking;
     about = "Serverless WASM runner with WebSocket subscription support"
 )]
 pub struct Opt {
-    /// Path to Yaml config for wasm module
+    /// Path to Yaml config for wasm module,
+    /// when omitted config is taken from GRAYAREA_MODULE_CONFIG env var
     #[structopt(parse(from_os_str))]
-    config: PathBuf,
+    config: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Compile wasm module of the config ahead of time into compiled modules cache
+    Compile {
+        /// Path to Yaml config for wasm module
+        #[structopt(parse(from_os_str))]
+        config: PathBuf,
+    },
 }
 
 impl Opt {
     pub async fn load_config(&self) -> Result<ModuleConfig> {
-        let buf = read(self.config.clone())
-            .await
-            .with_context(|| format!("Could not read config at {:?}", self.config))?;
-        let config: ModuleConfig = serde_yaml::from_slice(buf.as_slice())
-            .with_context(|| format!("Malformed module config {:?}", self.config))?;
+        let config = if let Ok(yaml) = std::env::var(MODULE_CONFIG_ENV_VAR) {
+            ModuleConfig::from_yaml(yaml.as_bytes()).with_context(|| {
+                format!("Malformed module config in {} env var", MODULE_CONFIG_ENV_VAR)
+            })?
+        } else if let Some(path) = self.config.as_ref() {
+            read_config(path).await?
+        } else {
+            return Err(anyhow!(
+                "Module config was not provided, either pass path to config or set {} env var",
+                MODULE_CONFIG_ENV_VAR
+            ));
+        };
         // Validation
         if config.stream.is_some() && !self.has_ipc() {
             Err(anyhow!(
@@ -35,6 +55,10 @@ impl Opt {
             Ok(config)
         }
     }
+    /// Name of the function replica run by this runtime, function name unless started by pipeline
+    pub fn replica(&self, config: &ModuleConfig) -> String {
+        std::env::var(REPLICA_ENV_VAR).unwrap_or_else(|_| config.name.clone())
+    }
     pub fn has_ipc(&self) -> bool {
         std::env::var(ipc_orchestrator::IPC_SERVER_ENV_VAR).is_ok()
     }
@@ -46,3 +70,12 @@ impl Opt {
         }
     }
 }
+
+/// Reads module config from Yaml file
+pub async fn read_config(path: &Path) -> Result<ModuleConfig> {
+    let buf = read(path)
+        .await
+        .with_context(|| format!("Could not read config at {:?}", path))?;
+    ModuleConfig::from_yaml(buf.as_slice())
+        .with_context(|| format!("Malformed module config {:?}", path))
+}
@@ -1,3 +1,6 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+
 pub struct Message {
     pub topic: u32,
     pub data: Vec<u8>,
@@ -7,6 +10,33 @@ pub struct Message {
 #[link(wasm_import_module = "io")]
 extern "C" {
     fn send_message_to_topic_idx(topic: u32, msg: u32, msg_len: u32);
+    fn send_message_to_topic_idx_with_headers(
+        topic: u32,
+        msg: u32,
+        msg_len: u32,
+        headers: u32,
+        headers_len: u32,
+    );
+    fn topic_idx(name: u32, name_len: u32) -> i32;
+    fn output_topics(buffer: u32, capacity: u32) -> u32;
+}
+
+/// Output topic resolved by name, see `Channel::topic`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Topic(u32);
+
+impl Topic {
+    /// Index of the topic in `output.topics` of module configuration
+    pub fn index(self) -> u32 {
+        self.0
+    }
+
+    /// Sends bytes to the topic
+    pub fn send(self, data: &[u8]) {
+        unsafe {
+            send_message_to_topic_idx(self.0, data.as_ptr() as u32, data.len() as u32);
+        }
+    }
 }
 
 /// Output channel connector for grayarea
@@ -18,9 +48,46 @@ extern "C" {
 /// };
 /// Channel::send_message(&message);
 /// ```
+///
+/// Topics might be addressed by name as declared in `output.topics` instead:
+///
+/// ```ignore
+/// let topic = Channel::topic("polo-log:v1")?;
+/// topic.send(b"hello world!");
+/// Channel::send_to("polo-log:v1", b"hello world!")?;
+/// ```
 pub struct Channel;
 
 impl Channel {
+    /// Resolves output topic by name, fails if topic is not declared in `output.topics`
+    pub fn topic(name: &str) -> Result<Topic> {
+        let idx = unsafe { topic_idx(name.as_ptr() as u32, name.len() as u32) };
+        if idx < 0 {
+            Err(anyhow!(
+                "topic {} is not declared in output topics {:?}",
+                name,
+                Channel::topics()
+            ))
+        } else {
+            Ok(Topic(idx as u32))
+        }
+    }
+
+    /// Output topics declared in module configuration, in order of their indexes
+    pub fn topics() -> Vec<String> {
+        let size = unsafe { output_topics(0, 0) } as usize;
+        let mut buffer = vec![0u8; size];
+        unsafe { output_topics(buffer.as_mut_ptr() as u32, size as u32) };
+        bincode::deserialize(&buffer).expect("host provides list of topics")
+    }
+
+    /// Sends bytes to topic resolved by name,
+    /// use `Channel::topic` to resolve it once when sending many messages
+    pub fn send_to(name: &str, data: &[u8]) -> Result<()> {
+        Channel::topic(name)?.send(data);
+        Ok(())
+    }
+
     /// Sends bytes to topic
     // TODO: rethink error handling
     pub fn send_message(message: &Message) {
@@ -32,4 +99,19 @@ impl Channel {
             );
         }
     }
+
+    /// Sends bytes to topic with user headers,
+    /// receiver gets those in `Context` along with headers set by host
+    pub fn send_message_with_headers(message: &Message, headers: &BTreeMap<String, String>) {
+        let headers = bincode::serialize(headers).expect("headers are serializable");
+        unsafe {
+            send_message_to_topic_idx_with_headers(
+                message.topic,
+                message.data.as_ptr() as u32,
+                message.data.len() as u32,
+                headers.as_ptr() as u32,
+                headers.len() as u32,
+            );
+        }
+    }
 }
@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Metadata of incoming message, wire compatible with `grayarea::header::Headers` of the host
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+    /// Function replica which produced the message, e.g. `processor-1`
+    pub source: String,
+    pub topic: String,
+    /// Sequence number of the message produced by the source function
+    pub seq: u64,
+    /// Time message was produced, milliseconds since unix epoch
+    pub timestamp: u64,
+    pub content_type: ContentType,
+    /// Key/value pairs set by the source function
+    pub user: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ContentType {
+    Binary,
+    /// Utf8 text, e.g. websocket text frame
+    Text,
+}
+
+impl Default for ContentType {
+    fn default() -> Self {
+        ContentType::Binary
+    }
+}
+
+/// Context of the message being processed
+pub struct Context {
+    pub headers: Headers,
+}
+
+impl Context {
+    /// Function replica which produced the message
+    pub fn source(&self) -> &str {
+        &self.headers.source
+    }
+
+    /// Topic message was received from
+    pub fn topic(&self) -> &str {
+        &self.headers.topic
+    }
+
+    /// Value of key set by the source function
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.user.get(key).map(String::as_str)
+    }
+}
+
+/// Decodes envelope passed by host into headers and message data
+pub(crate) fn decode(envelope: &[u8]) -> Result<(Headers, &[u8]), bincode::Error> {
+    bincode::deserialize(envelope)
+}
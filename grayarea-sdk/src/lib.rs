@@ -1,16 +1,29 @@
 pub mod channel;
+pub mod header;
+pub mod log;
 pub mod memory;
+mod status;
 pub mod websocket;
 
 pub use anyhow::Result;
+pub use header::{Context, Headers};
+pub use status::Status;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 
 thread_local! {
     static HANDLER: RefCell<Option<Box<dyn MessageHandler>>> = RefCell::new(None);
+    static TOPIC_HANDLERS: RefCell<BTreeMap<String, Box<dyn MessageHandler>>> =
+        RefCell::new(BTreeMap::new());
 }
 
 pub trait MessageHandler {
     fn on_message(&mut self, message: &[u8]) -> Result<()>;
+
+    /// Invoked on incoming message with its headers, calls `on_message` by default
+    fn on_message_with_context(&mut self, _context: &Context, message: &[u8]) -> Result<()> {
+        self.on_message(message)
+    }
 }
 
 /// Message handler is required to process incoming messages.
@@ -21,11 +34,36 @@ pub fn set_message_handler(new_handler: Box<dyn MessageHandler>) {
     HANDLER.with(|handler| handler.replace(Some(new_handler)));
 }
 
+/// Message handler of messages from given input topic, when function has several of those.
+/// Messages of topics without own handler are processed by handler set via `set_message_handler`.
+pub fn set_topic_handler(topic: &str, new_handler: Box<dyn MessageHandler>) {
+    TOPIC_HANDLERS.with(|handlers| handlers.borrow_mut().insert(topic.to_string(), new_handler));
+}
+
 // We store reference to handler as a static variable
 fn on_message_slice(message: &[u8]) -> Result<()> {
+    with_handler(|handler| handler.on_message(message))
+}
+
+fn on_envelope_slice(envelope: &[u8]) -> Result<()> {
+    let (headers, message) = header::decode(envelope)?;
+    let context = Context { headers };
+    let handled = TOPIC_HANDLERS.with(|handlers| {
+        handlers
+            .borrow_mut()
+            .get_mut(context.topic())
+            .map(|handler| handler.on_message_with_context(&context, message))
+    });
+    match handled {
+        Some(result) => result,
+        None => with_handler(|handler| handler.on_message_with_context(&context, message)),
+    }
+}
+
+fn with_handler(f: impl FnOnce(&mut Box<dyn MessageHandler>) -> Result<()>) -> Result<()> {
     HANDLER.with(|handler| {
         if let Some(handler) = &mut *handler.borrow_mut() {
-            handler.on_message(message)
+            f(handler)
         } else {
             Err(Box::new(std::io::Error::new(
                 std::io::ErrorKind::NotConnected,
@@ -37,23 +75,86 @@ fn on_message_slice(message: &[u8]) -> Result<()> {
 }
 
 /// This method is exposed to WASM runtime and invoked on incoming message
-/// Current implementation is panicing on failures which will shutdown host runner
-/// TODO: rethink error handling
+/// Message is in the buffer allocated by host via `memory::grayarea_alloc`,
+/// which is freed by host when this method returns.
+/// Returns acknowledgement of the message, see `Status`.
 #[no_mangle]
-fn on_message(ptr: *const u8, len: i32) {
+fn on_message(ptr: *const u8, len: i32) -> i64 {
     if ptr.is_null() {
         panic!("null pointer passed to on_message");
     }
     let msg = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
-    if let Err(err) = on_message_slice(msg) {
-        todo!("Failed to process message: {}", err);
-    };
+    acknowledge(on_message_slice(msg)).ack(0)
+}
+
+/// This method is exposed to WASM runtime and invoked on batch of incoming messages
+/// when function is configured with `batch_size` over 1.
+/// Batch is packed as frames of u32 little endian message length followed by message bytes.
+/// Processing stops on the first failed message, which is acknowledged along with its index.
+#[no_mangle]
+fn on_messages(ptr: *const u8, len: i32) -> i64 {
+    if ptr.is_null() {
+        panic!("null pointer passed to on_messages");
+    }
+    let batch = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    for_each_frame(batch, on_message_slice)
+}
+
+/// This method is exposed to WASM runtime and invoked on incoming message with headers,
+/// passed as envelope encoded by host
+#[no_mangle]
+fn on_envelope(ptr: *const u8, len: i32) -> i64 {
+    if ptr.is_null() {
+        panic!("null pointer passed to on_envelope");
+    }
+    let envelope = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    acknowledge(on_envelope_slice(envelope)).ack(0)
+}
+
+/// Same as `on_messages` with every frame being an envelope with headers
+#[no_mangle]
+fn on_envelopes(ptr: *const u8, len: i32) -> i64 {
+    if ptr.is_null() {
+        panic!("null pointer passed to on_envelopes");
+    }
+    let batch = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    for_each_frame(batch, on_envelope_slice)
+}
+
+fn for_each_frame(mut batch: &[u8], handler: fn(&[u8]) -> Result<()>) -> i64 {
+    let mut index = 0;
+    while batch.len() >= 4 {
+        let mut size = [0u8; 4];
+        size.copy_from_slice(&batch[..4]);
+        let size = u32::from_le_bytes(size) as usize;
+        if batch.len() < 4 + size {
+            panic!("truncated frame passed to message batch handler");
+        }
+        let status = acknowledge(handler(&batch[4..4 + size]));
+        if status != Status::Ok {
+            return status.ack(index);
+        }
+        batch = &batch[4 + size..];
+        index += 1;
+    }
+    Status::Ok.ack(0)
+}
+
+/// Status of processed message, failure is logged via host logger if it is set
+fn acknowledge(result: Result<()>) -> Status {
+    let status = Status::of(&result);
+    if let Err(err) = result {
+        ::log::error!("Failed to process message ({}): {}", status, err);
+    }
+    status
 }
 
 #[cfg(test)]
 mod tests {
+    use super::memory::{grayarea_alloc, grayarea_dealloc};
     use super::MessageHandler;
-    use super::{on_message, set_message_handler};
+    use super::{on_envelope, on_message, on_messages, set_message_handler, set_topic_handler};
+    use super::{Context, Headers, Status};
     use std::sync::{Arc, RwLock};
 
     #[derive(Debug, PartialEq)]
@@ -82,4 +183,109 @@ mod tests {
         on_message(b"message".as_ptr(), 7);
         assert_eq!(state.read().unwrap().count(), 1);
     }
+
+    #[test]
+    fn allocated_message() {
+        let state = Arc::new(RwLock::new(State(0)));
+        set_message_handler(Box::new(Processor(state.clone())));
+        let message = b"message";
+        let ptr = grayarea_alloc(message.len() as u32);
+        unsafe {
+            std::ptr::copy_nonoverlapping(message.as_ptr(), ptr, message.len());
+            on_message(ptr, message.len() as i32);
+            grayarea_dealloc(ptr, message.len() as u32);
+        }
+        assert_eq!(state.read().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn batch_of_messages() {
+        let state = Arc::new(RwLock::new(State(0)));
+        set_message_handler(Box::new(Processor(state.clone())));
+        let mut batch = Vec::new();
+        for message in [&b"first"[..], &b""[..], &b"third"[..]].iter() {
+            batch.extend_from_slice(&(message.len() as u32).to_le_bytes());
+            batch.extend_from_slice(message);
+        }
+        on_messages(batch.as_ptr(), batch.len() as i32);
+        assert_eq!(state.read().unwrap().count(), 3);
+    }
+
+    struct Topics(Arc<RwLock<Vec<String>>>);
+
+    impl MessageHandler for Topics {
+        fn on_message(&mut self, _: &[u8]) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("context is expected"))
+        }
+
+        fn on_message_with_context(
+            &mut self,
+            context: &Context,
+            message: &[u8],
+        ) -> anyhow::Result<()> {
+            let entry = format!("{}/{}:{:?}", context.source(), context.topic(), message);
+            self.0.write().unwrap().push(entry);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn message_with_context() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        set_message_handler(Box::new(Topics(received.clone())));
+        let headers = Headers {
+            source: "send".into(),
+            topic: "t1".into(),
+            ..Default::default()
+        };
+        let envelope = bincode::serialize(&(headers, &b"hi"[..])).unwrap();
+        on_envelope(envelope.as_ptr(), envelope.len() as i32);
+        assert_eq!(*received.read().unwrap(), vec!["send/t1:[104, 105]"]);
+    }
+
+    #[test]
+    fn topic_handlers() {
+        let (trades, other) = (
+            Arc::new(RwLock::new(State(0))),
+            Arc::new(RwLock::new(State(0))),
+        );
+        set_topic_handler("trades", Box::new(Processor(trades.clone())));
+        set_message_handler(Box::new(Processor(other.clone())));
+        for topic in ["trades", "book", "trades"].iter() {
+            let headers = Headers {
+                topic: topic.to_string(),
+                ..Default::default()
+            };
+            let envelope = bincode::serialize(&(headers, &b"hi"[..])).unwrap();
+            on_envelope(envelope.as_ptr(), envelope.len() as i32);
+        }
+        assert_eq!(trades.read().unwrap().count(), 2);
+        assert_eq!(other.read().unwrap().count(), 1);
+    }
+
+    struct Failing;
+
+    impl MessageHandler for Failing {
+        fn on_message(&mut self, message: &[u8]) -> anyhow::Result<()> {
+            match message {
+                b"retry" => Err(anyhow::Error::new(Status::Retry).context("not ready")),
+                b"bad" => Err(anyhow::anyhow!("malformed")),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn acknowledgements() {
+        set_message_handler(Box::new(Failing));
+        assert_eq!(on_message(b"ok".as_ptr(), 2), 0);
+        assert_eq!(on_message(b"bad".as_ptr(), 3), Status::Reject as i64);
+        let mut batch = Vec::new();
+        for message in [&b"ok"[..], &b"retry"[..], &b"ok"[..]].iter() {
+            batch.extend_from_slice(&(message.len() as u32).to_le_bytes());
+            batch.extend_from_slice(message);
+        }
+        let ack = on_messages(batch.as_ptr(), batch.len() as i32);
+        assert_eq!(ack, (1 << 32) | Status::Retry as i64);
+    }
 }
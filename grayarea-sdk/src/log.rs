@@ -0,0 +1,46 @@
+use ::log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+// For compiling with wasm32-wasi target
+#[link(wasm_import_module = "io")]
+extern "C" {
+    #[link_name = "log"]
+    fn host_log(level: u32, text: u32, len: u32);
+}
+
+/// Logger writing records of `log` crate macros to the host,
+/// which attaches function name, index of message being processed and timestamp
+///
+/// ```ignore
+/// grayarea::log::init(log::LevelFilter::Info).unwrap();
+/// log::info!("Processed {} messages", n);
+/// ```
+pub struct HostLogger;
+
+static LOGGER: HostLogger = HostLogger;
+
+/// Installs host logger, records above `level` are discarded by the module
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+    ::log::set_logger(&LOGGER).map(|()| ::log::set_max_level(level))
+}
+
+impl Log for HostLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= ::log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let text = record.args().to_string();
+        unsafe {
+            host_log(
+                record.level() as u32,
+                text.as_ptr() as u32,
+                text.len() as u32,
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
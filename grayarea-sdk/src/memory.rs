@@ -6,17 +6,35 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 // TODO: rewrite messages exchange to interface types rather than mem buffer
 
-// Create a static mutable byte buffer.
-// We will use for passing memory between our host and wasm.
-// NOTE: global `static mut` means we have to access it with unsafe
-// and manually ensure that only one mutable reference exists to it at a time
-// but for passing memory between a host and wasm should be fine.
-const WASM_MEMORY_BUFFER_SIZE: usize = 1024 * 1024;
-static mut WASM_MEMORY_BUFFER: [u8; WASM_MEMORY_BUFFER_SIZE] = [0; WASM_MEMORY_BUFFER_SIZE];
+/// Version of host <-> module ABI implemented by this SDK
+///
+/// 1. host writes messages to static buffer acquired via `buffer_pointer`
+/// 2. host allocates buffer for every message via `grayarea_alloc` and frees it
+///    via `grayarea_dealloc` after `on_message` returned
+/// 3. host delivers messages with headers via `on_envelope` and `on_envelopes`
+/// 4. message handlers return acknowledgement, see `Status`
+pub const ABI_VERSION: u32 = 4;
 
-// Function to return a pointer to our buffer
-// in wasm memory
 #[no_mangle]
-pub fn buffer_pointer() -> *const u8 {
-    unsafe { WASM_MEMORY_BUFFER.as_ptr() }
+pub fn grayarea_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+/// Allocates buffer of len bytes in wasm memory,
+/// host writes incoming message there before calling `on_message`
+#[no_mangle]
+pub fn grayarea_alloc(len: u32) -> *mut u8 {
+    let mut buffer = Vec::<u8>::with_capacity(len as usize);
+    let ptr = buffer.as_mut_ptr();
+    std::mem::forget(buffer);
+    ptr
+}
+
+/// Frees buffer allocated with `grayarea_alloc`
+///
+/// # Safety
+/// ptr and len shall be exactly as provided to and returned by `grayarea_alloc`
+#[no_mangle]
+pub unsafe fn grayarea_dealloc(ptr: *mut u8, len: u32) {
+    drop(Vec::from_raw_parts(ptr, 0, len as usize));
 }
@@ -0,0 +1,50 @@
+use std::fmt;
+
+/// Status of message processing acknowledged to host
+///
+/// Message handler might return it as an error to tell host how to handle the message,
+/// any other error is acknowledged as `Reject`.
+///
+/// ```ignore
+/// fn on_message(&mut self, message: &[u8]) -> Result<()> {
+///     let db = self.db.as_ref().ok_or(Status::Retry)?;
+///     ...
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum Status {
+    Ok = 0,
+    /// Message might be processed successfully later, host retries it according to on_error policy
+    Retry = 1,
+    /// Message can't be processed, host skips it or stops according to on_error policy
+    Reject = 2,
+    /// Function can't continue, host stops it
+    Fatal = 3,
+}
+
+impl Status {
+    pub(crate) fn of(result: &crate::Result<()>) -> Self {
+        match result {
+            Ok(()) => Status::Ok,
+            Err(err) => err
+                .downcast_ref::<Status>()
+                .copied()
+                .unwrap_or(Status::Reject),
+        }
+    }
+
+    /// Acknowledgement returned to host: status in low 32 bits
+    /// and index of the message within batch in high 32 bits
+    pub(crate) fn ack(self, index: u32) -> i64 {
+        ((index as i64) << 32) | self as i64
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for Status {}
@@ -1,9 +1,16 @@
+use crate::Result;
+use std::cell::RefCell;
+
 // For compiling with wasm32-wasi target
 #[link(wasm_import_module = "io")]
 extern "C" {
     fn send_websocket_message(msg: u32, len: u32);
 }
 
+thread_local! {
+    static SESSION: RefCell<Option<Box<dyn SessionHandler>>> = RefCell::new(None);
+}
+
 /// WebSocket connector for grayarea
 ///
 /// ```ignore
@@ -13,6 +20,8 @@ pub struct WebSocket;
 
 impl WebSocket {
     /// Sends provided bytes slice via websocket
+    /// Messages sent from main function are sent again by host on every reconnect,
+    /// ones sent from session handler are not.
     /// Please note, current implementation might panic on issue with websocket
     // TODO: rethink error handling
     pub fn send_message(message: &[u8]) {
@@ -20,4 +29,121 @@ impl WebSocket {
             send_websocket_message(message.as_ptr() as u32, message.len() as u32);
         }
     }
+
+    /// Session handler is notified of websocket connection events,
+    /// e.g. to log in and subscribe once connected
+    pub fn set_session_handler(new_handler: Box<dyn SessionHandler>) {
+        SESSION.with(|handler| handler.replace(Some(new_handler)));
+    }
+}
+
+/// Handler of the websocket connection session, failures are logged via host logger.
+///
+/// Messages received from websocket are delivered to `on_message` only when
+/// grayarea-sdk is built with `ws_messages` feature, otherwise host does not pass them to module.
+///
+/// ```ignore
+/// impl SessionHandler for Login {
+///     fn on_connect(&mut self, _url: &str) -> Result<()> {
+///         WebSocket::send_message(b"{\"op\": \"challenge\"}");
+///         Ok(())
+///     }
+///
+///     fn on_message(&mut self, message: &[u8]) -> Result<()> {
+///         if let Some(challenge) = parse_challenge(message) {
+///             WebSocket::send_message(&self.sign(challenge));
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait SessionHandler {
+    fn on_connect(&mut self, _url: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_message(&mut self, _message: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_disconnect(&mut self, _reason: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn with_session(hook: &str, f: impl FnOnce(&mut Box<dyn SessionHandler>) -> Result<()>) {
+    SESSION.with(|handler| {
+        if let Some(handler) = &mut *handler.borrow_mut() {
+            if let Err(err) = f(handler) {
+                ::log::error!("Session handler {} failed: {}", hook, err);
+            }
+        }
+    })
+}
+
+/// This method is exposed to WASM runtime and invoked once websocket is connected,
+/// url is passed in the buffer allocated by host
+#[no_mangle]
+fn on_connect(ptr: *const u8, len: i32) {
+    if ptr.is_null() {
+        panic!("null pointer passed to on_connect");
+    }
+    let url = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    let url = String::from_utf8_lossy(url);
+    with_session("on_connect", |handler| handler.on_connect(&url));
+}
+
+/// This method is exposed to WASM runtime and invoked on every message received from websocket
+#[cfg(feature = "ws_messages")]
+#[no_mangle]
+fn on_ws_message(ptr: *const u8, len: i32) {
+    if ptr.is_null() {
+        panic!("null pointer passed to on_ws_message");
+    }
+    let message = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    with_session("on_ws_message", |handler| handler.on_message(message));
+}
+
+/// This method is exposed to WASM runtime and invoked once websocket connection is lost
+#[no_mangle]
+fn on_disconnect(ptr: *const u8, len: i32) {
+    if ptr.is_null() {
+        panic!("null pointer passed to on_disconnect");
+    }
+    let reason = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    let reason = String::from_utf8_lossy(reason);
+    with_session("on_disconnect", |handler| handler.on_disconnect(&reason));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{on_connect, on_disconnect, SessionHandler, WebSocket};
+    use std::sync::{Arc, RwLock};
+
+    struct Events(Arc<RwLock<Vec<String>>>);
+
+    impl SessionHandler for Events {
+        fn on_connect(&mut self, url: &str) -> crate::Result<()> {
+            self.0.write().unwrap().push(format!("connect {}", url));
+            Ok(())
+        }
+
+        fn on_disconnect(&mut self, reason: &str) -> crate::Result<()> {
+            self.0.write().unwrap().push(format!("disconnect {}", reason));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn session_handler() {
+        let events = Arc::new(RwLock::new(Vec::new()));
+        WebSocket::set_session_handler(Box::new(Events(events.clone())));
+        let (url, reason) = ("wss://a.com/", "closed by server");
+        on_connect(url.as_ptr(), url.len() as i32);
+        on_disconnect(reason.as_ptr(), reason.len() as i32);
+        assert_eq!(
+            *events.read().unwrap(),
+            vec!["connect wss://a.com/", "disconnect closed by server"]
+        );
+    }
 }
@@ -0,0 +1,114 @@
+use crate::config::cache_dir;
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use wasmer_runtime::cache::{Cache, FileSystemCache, WasmHash};
+use wasmer_runtime::error::CacheError;
+use wasmer_runtime::{compile, Module};
+
+/// On-disk cache of compiled modules
+///
+/// Compiled artifacts are stored in `<cache>/compiled/<wasmer version>`
+/// keyed by hash of module bytes, so that upgraded engine never picks up stale artifacts.
+/// Entries which fail to load are compiled again and replaced.
+pub struct ModuleCache {
+    dir: PathBuf,
+    cache: FileSystemCache,
+}
+
+/// How module was obtained from the cache
+#[derive(Debug, PartialEq)]
+pub enum CacheStatus {
+    /// Loaded compiled module
+    Hit,
+    /// Module was not cached yet and was compiled
+    Compiled,
+    /// Cached entry was stale or corrupted and was compiled again
+    Rebuilt,
+}
+
+impl ModuleCache {
+    pub fn open() -> Result<Self> {
+        Self::open_in(cache_dir()?)
+    }
+
+    /// Opens cache of compiled modules kept in the given cache directory
+    pub fn open_in(cache_dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = cache_dir
+            .as_ref()
+            .join("compiled")
+            .join(wasmer_runtime::VERSION);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create compiled modules cache {:?}", dir))?;
+        // Artifacts are loaded as executable code without verification,
+        // cache directory shall be trusted as much as modules themselves
+        let cache = unsafe { FileSystemCache::new(&dir)? };
+        Ok(ModuleCache { dir, cache })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Loads compiled module from the cache, compiles and stores it on a miss
+    pub fn load_or_compile(&mut self, wasm_bytes: &[u8]) -> Result<(Module, CacheStatus)> {
+        let key = WasmHash::generate(wasm_bytes);
+        let status = match self.cache.load(key) {
+            Ok(module) => return Ok((module, CacheStatus::Hit)),
+            // missing entry
+            Err(CacheError::IoError(_)) => CacheStatus::Compiled,
+            Err(_) => CacheStatus::Rebuilt,
+        };
+        let module = compile(wasm_bytes).map_err(|err| anyhow::anyhow!("{}", err))?;
+        self.cache
+            .store(key, module.clone())
+            .map_err(|err| anyhow::anyhow!("Could not store compiled module: {:?}", err))?;
+        Ok((module, status))
+    }
+}
+
+impl fmt::Display for CacheStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheStatus::Hit => write!(f, "loaded from cache"),
+            CacheStatus::Compiled => write!(f, "compiled"),
+            CacheStatus::Rebuilt => write!(f, "compiled again replacing stale cache entry"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheStatus, ModuleCache};
+    use std::path::Path;
+
+    /// Module of one empty function: (module (func (export "f")))
+    const WASM: &[u8] =
+        b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0\x07\x05\x01\x01f\0\0\x0a\x04\x01\x02\0\x0b";
+
+    /// Overwrites all the files in the directory tree
+    fn corrupt(dir: &Path) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                corrupt(&path);
+            } else {
+                std::fs::write(path, b"garbage").unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_entry_is_rebuilt() {
+        let dir = std::env::temp_dir().join(format!("grayarea_test_cache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut cache = ModuleCache::open_in(&dir).unwrap();
+        let status = |cache: &mut ModuleCache| cache.load_or_compile(WASM).unwrap().1;
+        assert_eq!(status(&mut cache), CacheStatus::Compiled);
+        assert_eq!(status(&mut cache), CacheStatus::Hit);
+        corrupt(cache.dir());
+        assert_eq!(status(&mut cache), CacheStatus::Rebuilt);
+        assert_eq!(status(&mut cache), CacheStatus::Hit);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
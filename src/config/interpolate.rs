@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Context, Result};
+use serde_yaml::Value;
+
+const SECRET_FILE: &str = "secret_file";
+/// Prefix of environment variable holding a secret, `${secret:VAR}`
+const SECRET_VAR: &str = "secret:";
+const MASK: &str = "***";
+
+/// Values substituted into config from secret files and environment variables marked as secret.
+///
+/// Those are masked whenever config values are printed.
+#[derive(Default, Clone)]
+pub struct Secrets(Vec<String>);
+
+impl Secrets {
+    /// Replaces all occurrences of secret values in text with `***`
+    pub fn mask(&self, text: &str) -> String {
+        self.0
+            .iter()
+            .filter(|secret| !secret.is_empty())
+            .fold(text.to_string(), |text, secret| text.replace(secret.as_str(), MASK))
+    }
+
+    fn add(&mut self, secret: String) {
+        if !self.0.contains(&secret) {
+            self.0.push(secret);
+            // longest first, so that secrets containing other secrets are masked completely
+            self.0.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        }
+    }
+}
+
+/// Resolves environment variables and secret file references in all string values
+///
+/// - `${VAR}` is replaced with the value of environment variable `VAR`, fails if it is not set
+/// - `${VAR:-default}` is replaced with `default` when `VAR` is not set
+/// - `${secret:VAR}` is replaced with the value of `VAR` kept as a secret
+/// - `{ secret_file: "path" }` mapping is replaced with the contents of the file kept as a secret
+pub fn interpolate(value: &mut Value) -> Result<Secrets> {
+    let mut secrets = Secrets::default();
+    interpolate_value(value, &mut secrets)?;
+    Ok(secrets)
+}
+
+fn interpolate_value(value: &mut Value, secrets: &mut Secrets) -> Result<()> {
+    match value {
+        Value::String(s) => *s = interpolate_str(s, secrets)?,
+        Value::Sequence(seq) => {
+            for v in seq.iter_mut() {
+                interpolate_value(v, secrets)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            if let Some(path) = secret_file(mapping) {
+                let path = interpolate_str(path, secrets)?;
+                let secret = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Could not read secret file {:?}", path))?
+                    .trim_end()
+                    .to_string();
+                secrets.add(secret.clone());
+                *value = Value::String(secret);
+            } else {
+                for (_, v) in mapping.iter_mut() {
+                    interpolate_value(v, secrets)?;
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn secret_file(mapping: &serde_yaml::Mapping) -> Option<&str> {
+    if mapping.len() != 1 {
+        return None;
+    }
+    mapping
+        .get(&Value::String(SECRET_FILE.into()))
+        .and_then(Value::as_str)
+}
+
+fn interpolate_str(s: &str, secrets: &mut Secrets) -> Result<String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated ${{ in config value {:?}", s))?;
+        let expr = &rest[start + 2..start + end];
+        let (secret, expr) = if expr.starts_with(SECRET_VAR) {
+            (true, &expr[SECRET_VAR.len()..])
+        } else {
+            (false, expr)
+        };
+        let (name, default) = match expr.find(":-") {
+            Some(idx) => (&expr[..idx], Some(&expr[idx + 2..])),
+            None => (expr, None),
+        };
+        if name.is_empty() {
+            return Err(anyhow!("Empty variable name in config value {:?}", s));
+        }
+        match (std::env::var(name), default) {
+            (Ok(var), _) => {
+                if secret {
+                    secrets.add(var.clone());
+                }
+                result.push_str(&var);
+            }
+            (Err(_), Some(default)) => result.push_str(default),
+            (Err(_), None) => {
+                return Err(anyhow!(
+                    "Environment variable {} used in config is not set",
+                    name
+                ))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::interpolate;
+    use serde_yaml::Value;
+
+    fn resolve(yaml: &str) -> (Value, super::Secrets) {
+        let mut value: Value = serde_yaml::from_str(yaml).unwrap();
+        let secrets = interpolate(&mut value).unwrap();
+        (value, secrets)
+    }
+
+    #[test]
+    fn env_vars() {
+        std::env::set_var("GRAYAREA_TEST_KEY", "s3cr3t");
+        std::env::set_var("GRAYAREA_TEST_HOST", "host");
+        let (value, secrets) = resolve(
+            "url: \"wss://${GRAYAREA_TEST_HOST}/?key=${secret:GRAYAREA_TEST_KEY}&pair=${GRAYAREA_TEST_PAIR:-BTC}\"",
+        );
+        let url = value["url"].as_str().unwrap();
+        assert_eq!(url, "wss://host/?key=s3cr3t&pair=BTC");
+        assert_eq!(secrets.mask(url), "wss://host/?key=***&pair=BTC");
+    }
+
+    #[test]
+    fn plain_env_var_not_masked() {
+        std::env::set_var("GRAYAREA_TEST_PLAIN", "USDT_BTC");
+        let (value, secrets) = resolve("args: [\"${GRAYAREA_TEST_PLAIN}\"]");
+        assert_eq!(value["args"][0].as_str(), Some("USDT_BTC"));
+        assert_eq!(secrets.mask("pair USDT_BTC"), "pair USDT_BTC");
+    }
+
+    #[test]
+    fn missing_env_var() {
+        let mut value: Value = serde_yaml::from_str("args: [\"${GRAYAREA_TEST_MISSING}\"]").unwrap();
+        assert!(interpolate(&mut value).is_err());
+    }
+
+    #[test]
+    fn secret_file() {
+        let path = std::env::temp_dir().join("grayarea_test_secret");
+        std::fs::write(&path, "api-key\n").unwrap();
+        let (value, secrets) = resolve(&format!("args: [{{ secret_file: {:?} }}, \"plain\"]", path));
+        assert_eq!(value["args"][0].as_str(), Some("api-key"));
+        assert_eq!(value["args"][1].as_str(), Some("plain"));
+        assert_eq!(secrets.mask("login api-key"), "login ***");
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::fs::{create_dir_all, read, rename, write};
+
+/// Env var overriding location of local modules registry, `~/.grayarea/registry` by default
+pub const REGISTRY_ENV_VAR: &str = "GRAYAREA_REGISTRY";
+/// Env var overriding location of fetched modules cache, `~/.grayarea/cache` by default
+pub const CACHE_ENV_VAR: &str = "GRAYAREA_CACHE";
+
+/// WASM module source
+///
+/// Module might be loaded from local path, `file://` or `http(s)://` url,
+/// or from the local registry directory where it is stored as `<name>/<version>.wasm`.
+/// Optional `sha256` pin is checked against module bytes before the module is used.
+/// Fetched modules are cached by their hash, so only pinned modules are loaded from the cache,
+/// unpinned ones are downloaded on every start as the module behind url might change.
+///
+/// # Example
+/// ```yml
+/// module:
+///   url: "https://example.com/modules/handshaker.wasm"
+///   sha256: "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+/// ```
+/// ```yml
+/// module:
+///   registry: "polo-websocket:1.0"
+/// ```
+#[derive(Deserialize)]
+pub struct Module {
+    #[serde(flatten)]
+    pub source: WasmSource,
+    /// Hex encoded sha256 of the module bytes
+    pub sha256: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub enum WasmSource {
+    #[serde(rename = "path")]
+    Path(PathBuf),
+    #[serde(rename = "url")]
+    Url(url::Url),
+    /// Module `name:version` in the local registry
+    #[serde(rename = "registry")]
+    Registry(String),
+}
+
+/// Directories of local modules registry and fetched modules cache
+pub struct ModuleDirs {
+    pub registry: PathBuf,
+    pub cache: PathBuf,
+}
+
+impl ModuleDirs {
+    /// Directories set via `GRAYAREA_REGISTRY` and `GRAYAREA_CACHE`, `~/.grayarea` by default
+    pub fn from_env() -> Result<Self> {
+        Ok(ModuleDirs {
+            registry: base_dir(REGISTRY_ENV_VAR, "registry")?,
+            cache: cache_dir()?,
+        })
+    }
+}
+
+impl Module {
+    /// Loads module bytes, checking them against the pin if provided.
+    /// Registry and cache directories are only located for modules which aren't local.
+    pub async fn load(&self) -> Result<Vec<u8>> {
+        let bytes = match &self.source {
+            WasmSource::Path(path) => read_file(path).await?,
+            _ => return self.load_from(&ModuleDirs::from_env()?).await,
+        };
+        self.check(&bytes)?;
+        Ok(bytes)
+    }
+
+    /// Loads module bytes using given registry and cache directories
+    pub async fn load_from(&self, dirs: &ModuleDirs) -> Result<Vec<u8>> {
+        let bytes = match &self.source {
+            WasmSource::Path(path) => read_file(path).await?,
+            WasmSource::Registry(name) => read_file(&registry_path(&dirs.registry, name)?).await?,
+            WasmSource::Url(url) if url.scheme() == "file" => {
+                let path = url
+                    .to_file_path()
+                    .map_err(|_| anyhow!("Malformed file url {}", url))?;
+                read_file(&path).await?
+            }
+            WasmSource::Url(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                return self.fetch(url, &dirs.cache).await
+            }
+            WasmSource::Url(url) => {
+                return Err(anyhow!("Unsupported module url scheme {}", url.scheme()))
+            }
+        };
+        self.check(&bytes)?;
+        Ok(bytes)
+    }
+
+    fn check(&self, bytes: &[u8]) -> Result<()> {
+        match self.sha256.as_ref() {
+            Some(pin) if !pin.eq_ignore_ascii_case(&sha256(bytes)) => Err(anyhow!(
+                "Module sha256 {} does not match pinned {}",
+                sha256(bytes),
+                pin
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads module from cache if it is pinned and was fetched before,
+    /// otherwise downloads it and puts to the cache
+    async fn fetch(&self, url: &url::Url, cache: &Path) -> Result<Vec<u8>> {
+        if let Some(pin) = self.sha256.as_ref() {
+            let cached = cache.join(format!("{}.wasm", pin.to_lowercase()));
+            if let Ok(bytes) = read(&cached).await {
+                // corrupted cache entry is fetched again
+                if self.check(&bytes).is_ok() {
+                    return Ok(bytes);
+                }
+            }
+        }
+        let bytes = download(url).await?;
+        self.check(&bytes)
+            .with_context(|| format!("Module downloaded from {}", url))?;
+        // Write to temp file first, so that concurrent runtimes never read partial module
+        create_dir_all(cache).await?;
+        let hash = sha256(&bytes);
+        let tmp = cache.join(format!("{}.{}.tmp", hash, std::process::id()));
+        write(&tmp, &bytes).await?;
+        rename(&tmp, cache.join(format!("{}.wasm", hash))).await?;
+        Ok(bytes)
+    }
+}
+
+async fn read_file(path: &Path) -> Result<Vec<u8>> {
+    read(path)
+        .await
+        .with_context(|| format!("Could not read WASM plugin at {:?}", path))
+}
+
+#[cfg(feature = "http")]
+async fn download(url: &url::Url) -> Result<Vec<u8>> {
+    let response = reqwest::get(url.clone())
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Could not fetch WASM plugin from {}", url))?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+#[cfg(not(feature = "http"))]
+async fn download(url: &url::Url) -> Result<Vec<u8>> {
+    Err(anyhow!(
+        "Could not fetch WASM plugin from {}: grayarea was built without http feature",
+        url
+    ))
+}
+
+/// Directory of fetched and compiled modules cache
+pub fn cache_dir() -> Result<PathBuf> {
+    base_dir(CACHE_ENV_VAR, "cache")
+}
+
+/// Path of module `name:version` in the registry, name and version can't escape the registry
+fn registry_path(registry: &Path, name: &str) -> Result<PathBuf> {
+    let valid = |part: &str| {
+        !part.is_empty() && !part.contains("..") && !part.contains('/') && !part.contains('\\')
+    };
+    let mut parts = name.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(module), Some(version)) if valid(module) && valid(version) => {
+            Ok(registry.join(module).join(format!("{}.wasm", version)))
+        }
+        _ => Err(anyhow!(
+            "Registry module {} shall be specified as name:version without path separators",
+            name
+        )),
+    }
+}
+
+fn base_dir(env_var: &str, default: &str) -> Result<PathBuf> {
+    match std::env::var_os(env_var) {
+        Some(dir) => Ok(dir.into()),
+        None => dirs::home_dir()
+            .map(|home| home.join(".grayarea").join(default))
+            .ok_or_else(|| anyhow!("Could not locate home directory, please set {}", env_var)),
+    }
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{registry_path, sha256, Module, ModuleDirs};
+    use std::path::{Path, PathBuf};
+
+    /// Fresh registry and cache directories of the test
+    fn dirs(test: &str) -> ModuleDirs {
+        let base = std::env::temp_dir()
+            .join(format!("grayarea-module-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        ModuleDirs {
+            registry: base.join("registry"),
+            cache: base.join("cache"),
+        }
+    }
+
+    fn write(path: &Path, bytes: &[u8]) -> PathBuf {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, bytes).unwrap();
+        path.to_path_buf()
+    }
+
+    fn module(yaml: &str) -> Module {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn path_and_file_url() {
+        let dirs = dirs("path");
+        let path = write(&dirs.registry.join("plain.wasm"), b"\0asm path");
+        let by_path = module(&format!("{{path: {:?}}}", path));
+        assert_eq!(by_path.load_from(&dirs).await.unwrap(), b"\0asm path".to_vec());
+        let url = url::Url::from_file_path(&path).unwrap();
+        let by_url = module(&format!("{{url: {:?}}}", url.as_str()));
+        assert_eq!(by_url.load_from(&dirs).await.unwrap(), b"\0asm path".to_vec());
+    }
+
+    #[tokio::test]
+    async fn registry() {
+        let dirs = dirs("registry");
+        write(&dirs.registry.join("polo").join("1.0.wasm"), b"\0asm registry");
+        let bytes = module("{registry: 'polo:1.0'}").load_from(&dirs).await.unwrap();
+        assert_eq!(bytes, b"\0asm registry".to_vec());
+        assert!(module("{registry: 'polo:2.0'}").load_from(&dirs).await.is_err());
+    }
+
+    #[test]
+    fn registry_path_escape() {
+        let registry = Path::new("registry");
+        assert_eq!(
+            registry_path(registry, "polo:1.0").unwrap(),
+            registry.join("polo").join("1.0.wasm")
+        );
+        for name in &["polo", ":1.0", "polo:", "..:1.0", "polo:../../x", "a/b:1.0", "polo:1/0"] {
+            assert!(registry_path(registry, name).is_err(), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn pin_mismatch() {
+        let dirs = dirs("pin");
+        let path = write(&dirs.registry.join("pinned.wasm"), b"\0asm pinned");
+        let pinned = module(&format!("{{path: {:?}, sha256: {:?}}}", path, sha256(b"\0asm pinned")));
+        assert!(pinned.load_from(&dirs).await.is_ok());
+        let wrong = module(&format!("{{path: {:?}, sha256: {:?}}}", path, sha256(b"reviewed")));
+        assert!(wrong.load_from(&dirs).await.is_err());
+    }
+
+    #[cfg(feature = "http")]
+    mod http {
+        use super::{dirs, module, sha256};
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        /// Serves given body to every request
+        fn serve(body: &'static [u8]) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut request = [0u8; 1024];
+                    let _ = stream.read(&mut request).unwrap();
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .unwrap();
+                    stream.write_all(body).unwrap();
+                }
+            });
+            format!("http://{}/module.wasm", addr)
+        }
+
+        #[tokio::test]
+        async fn http_module_pinned() {
+            let dirs = dirs("http");
+            let body = b"\0asm fake module";
+            let url = serve(body);
+            let pinned = format!("{{url: {:?}, sha256: {:?}}}", url, sha256(body));
+
+            let bytes = module(&pinned).load_from(&dirs).await.unwrap();
+            assert_eq!(bytes, body.to_vec());
+            assert!(dirs.cache.join(format!("{}.wasm", sha256(body))).exists());
+
+            let wrong = format!("{{url: {:?}, sha256: {:?}}}", url, sha256(b"reviewed module"));
+            assert!(module(&wrong).load_from(&dirs).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use crate::header;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Key messages are partitioned by between replicas of the function,
+/// messages with the same key are delivered to the same replica in order
+///
+/// # Example
+/// ```yml
+/// partition_by:
+///   header: "pair"                # user header set by producer
+/// partition_by:
+///   bytes: { start: 0, end: 8 }   # range of the payload
+/// partition_by: "payload"         # whole payload
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum PartitionBy {
+    #[serde(alias = "header")]
+    Header(String),
+    /// Range of payload bytes, shorter payloads are keyed by bytes they have within range
+    #[serde(alias = "bytes")]
+    Bytes { start: usize, end: usize },
+    #[serde(alias = "payload")]
+    Payload,
+}
+
+impl PartitionBy {
+    /// Index of replica the message envelope is routed to,
+    /// malformed envelopes and messages without key are routed to the first one
+    pub fn replica(&self, envelope: &[u8], replicas: usize) -> usize {
+        let (headers, data) = match header::decode(envelope) {
+            Ok(message) => message,
+            Err(_) => return 0,
+        };
+        let key = match self {
+            PartitionBy::Header(name) => match headers.user.get(name) {
+                Some(value) => value.as_bytes(),
+                None => return 0,
+            },
+            PartitionBy::Bytes { start, end } => {
+                let end = (*end).min(data.len());
+                data.get(*start..end).unwrap_or_default()
+            }
+            PartitionBy::Payload => data,
+        };
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % replicas.max(1) as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PartitionBy;
+    use crate::header::{encode, ContentType, Headers};
+
+    fn message(pair: Option<&str>, data: &[u8]) -> Vec<u8> {
+        let mut headers = Headers::new("send", "trades", 0, ContentType::Binary);
+        if let Some(pair) = pair {
+            headers.user.insert("pair".into(), pair.into());
+        }
+        encode(&headers, data).unwrap()
+    }
+
+    #[test]
+    fn same_key_same_replica() {
+        let by_header: PartitionBy = serde_yaml::from_str("header: pair").unwrap();
+        let a = by_header.replica(&message(Some("USDT_BTC"), b"1"), 4);
+        assert_eq!(by_header.replica(&message(Some("USDT_BTC"), b"2"), 4), a);
+        assert_eq!(by_header.replica(&message(None, b"3"), 4), 0);
+
+        let by_bytes: PartitionBy = serde_yaml::from_str("bytes: {start: 0, end: 3}").unwrap();
+        let b = by_bytes.replica(&message(None, b"BTC:1"), 4);
+        assert_eq!(by_bytes.replica(&message(None, b"BTC:2"), 4), b);
+        assert!(b < 4);
+
+        let by_payload: PartitionBy = serde_yaml::from_str("payload").unwrap();
+        assert_eq!(by_payload, PartitionBy::Payload);
+        assert_eq!(by_payload.replica(&message(None, b"x"), 1), 0);
+    }
+}
@@ -0,0 +1,67 @@
+use super::{ModuleConfig, REPLICA_ENV_VAR};
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Guest path of the private scratch directory
+pub const SCRATCH_DIR: &str = "/scratch";
+
+/// WASI environment of the module: arguments, environment variables and directories
+pub struct Wasi {
+    pub args: Vec<Vec<u8>>,
+    pub envs: Vec<Vec<u8>>,
+    pub preopened: Vec<PathBuf>,
+    pub mapped_dirs: Vec<(String, PathBuf)>,
+}
+
+impl ModuleConfig {
+    /// Resolves WASI environment of the module
+    ///
+    /// Fails if any of preopened or mapped directories does not exist.
+    /// Creates private scratch directory of the function if it does not exist yet.
+    pub fn wasi(&self) -> Result<Wasi> {
+        let envs = self
+            .env
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value).into_bytes())
+            .collect();
+        for dir in self.preopen.iter() {
+            check_dir(dir).context("preopen")?;
+        }
+        let mut mapped_dirs = Vec::with_capacity(self.map_dirs.len() + 1);
+        for (alias, dir) in self.map_dirs.iter() {
+            check_dir(dir).with_context(|| format!("map_dirs.{}", alias))?;
+            mapped_dirs.push((alias.clone(), dir.clone()));
+        }
+        if !self.map_dirs.contains_key(SCRATCH_DIR) {
+            let scratch = self.scratch_dir();
+            std::fs::create_dir_all(&scratch)
+                .with_context(|| format!("Could not create scratch directory {:?}", scratch))?;
+            mapped_dirs.push((SCRATCH_DIR.to_string(), scratch));
+        }
+        Ok(Wasi {
+            args: self.args_as_bytes(),
+            envs,
+            preopened: self.preopen.clone(),
+            mapped_dirs,
+        })
+    }
+
+    /// Host directory mapped to the module as `/scratch`, keyed by name of the function replica
+    /// set by the pipeline, so that replicas don't overwrite files of each other
+    pub fn scratch_dir(&self) -> PathBuf {
+        self.scratch.clone().unwrap_or_else(|| {
+            let replica = std::env::var(REPLICA_ENV_VAR).unwrap_or_else(|_| self.name.clone());
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '#';
+            let dir = replica.replace(|c: char| !valid(c), "_");
+            std::env::temp_dir().join("grayarea").join(dir)
+        })
+    }
+}
+
+fn check_dir(dir: &Path) -> Result<()> {
+    if dir.is_dir() {
+        Ok(())
+    } else {
+        Err(anyhow!("Directory {:?} does not exist", dir))
+    }
+}
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::SystemTime;
+
+/// Topic reserved for lifecycle events of function streams, those are collected by the pipeline host
+pub const CONNECTION_TOPIC: &str = "grayarea.connection";
+
+/// Lifecycle event of the stream connection of input function
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ConnectionEvent {
+    pub function: String,
+    /// Url of the stream with secrets masked
+    pub url: String,
+    pub state: ConnectionState,
+    pub timestamp: SystemTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// Connection established, `attempt` is number of failed attempts before this one
+    Connected { attempt: u32 },
+    /// Connection attempt failed
+    Failed { attempt: u32, error: String },
+    /// Established connection was lost
+    Disconnected { reason: String },
+    /// Connection is open but tripped heartbeat check, it is dropped and established again
+    Alert { reason: String },
+}
+
+impl ConnectionEvent {
+    pub fn new(function: &str, url: &str, state: ConnectionState) -> Self {
+        ConnectionEvent {
+            function: function.to_string(),
+            url: url.to_string(),
+            state,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    /// Level the event is logged with by the pipeline host
+    pub fn level(&self) -> log::Level {
+        match self.state {
+            ConnectionState::Connected { .. } => log::Level::Info,
+            ConnectionState::Alert { .. } => log::Level::Error,
+            _ => log::Level::Warn,
+        }
+    }
+}
+
+/// Event is rendered with the time it happened in the runtime
+impl fmt::Display for ConnectionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ", humantime::format_rfc3339_millis(self.timestamp))?;
+        match &self.state {
+            ConnectionState::Connected { attempt: 0 } => write!(f, "connected to {}", self.url),
+            ConnectionState::Connected { attempt } => write!(
+                f,
+                "connected to {} after {} failed attempts",
+                self.url, attempt
+            ),
+            ConnectionState::Failed { attempt, error } => write!(
+                f,
+                "attempt {} to connect to {} failed: {}",
+                attempt, self.url, error
+            ),
+            ConnectionState::Disconnected { reason } => {
+                write!(f, "disconnected from {}: {}", self.url, reason)
+            }
+            ConnectionState::Alert { reason } => {
+                write!(f, "connection to {} is dead: {}", self.url, reason)
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// User headers set on message published to dead letter topic
+pub const DEAD_LETTER_TOPIC: &str = "dead_letter.topic";
+pub const DEAD_LETTER_FUNCTION: &str = "dead_letter.function";
+pub const DEAD_LETTER_ERROR: &str = "dead_letter.error";
+pub const DEAD_LETTER_ATTEMPTS: &str = "dead_letter.attempts";
+
+/// Metadata of a message carried along with its data between functions
+///
+/// Messages are passed between functions as bincode encoded `(Headers, data)` envelope,
+/// grayarea-sdk keeps wire compatible copy of this structure.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+    /// Function replica which produced the message, e.g. `processor-1`
+    pub source: String,
+    pub topic: String,
+    /// Sequence number of the message produced by the source function
+    pub seq: u64,
+    /// Time message was produced, milliseconds since unix epoch
+    pub timestamp: u64,
+    pub content_type: ContentType,
+    /// Key/value pairs set by the source function
+    pub user: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ContentType {
+    Binary,
+    /// Utf8 text, e.g. websocket text frame
+    Text,
+}
+
+impl Default for ContentType {
+    fn default() -> Self {
+        ContentType::Binary
+    }
+}
+
+impl Headers {
+    /// Headers of the message produced now
+    pub fn new(source: &str, topic: &str, seq: u64, content_type: ContentType) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or(0);
+        Headers {
+            source: source.to_string(),
+            topic: topic.to_string(),
+            seq,
+            timestamp,
+            content_type,
+            user: BTreeMap::new(),
+        }
+    }
+
+    /// Headers of the message function failed to process, published to dead letter `topic`
+    pub fn dead_letter(mut self, topic: &str, function: &str, error: &str, attempts: u32) -> Self {
+        let original = std::mem::replace(&mut self.topic, topic.to_string());
+        self.user.insert(DEAD_LETTER_TOPIC.to_string(), original);
+        self.user
+            .insert(DEAD_LETTER_FUNCTION.to_string(), function.to_string());
+        self.user
+            .insert(DEAD_LETTER_ERROR.to_string(), error.to_string());
+        self.user
+            .insert(DEAD_LETTER_ATTEMPTS.to_string(), attempts.to_string());
+        self
+    }
+
+    /// Headers of dead-lettered message to publish it to original topic again,
+    /// `None` if message was not dead-lettered
+    pub fn restore(mut self) -> Option<Self> {
+        self.topic = self.user.remove(DEAD_LETTER_TOPIC)?;
+        self.user.remove(DEAD_LETTER_FUNCTION);
+        self.user.remove(DEAD_LETTER_ERROR);
+        self.user.remove(DEAD_LETTER_ATTEMPTS);
+        Some(self)
+    }
+}
+
+/// Encodes message data with headers into envelope
+pub fn encode(headers: &Headers, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Ok(bincode::serialize(&(headers, data))?)
+}
+
+/// Decodes envelope into headers and data, data is not copied
+pub fn decode(envelope: &[u8]) -> anyhow::Result<(Headers, &[u8])> {
+    Ok(bincode::deserialize(envelope)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, ContentType, Headers, DEAD_LETTER_ATTEMPTS, DEAD_LETTER_TOPIC};
+
+    #[test]
+    fn envelope() {
+        let mut headers = Headers::new("send", "topic1", 7, ContentType::Text);
+        headers.user.insert("pair".into(), "USDT_BTC".into());
+        let envelope = encode(&headers, b"hello").unwrap();
+        let (decoded, data) = decode(&envelope).unwrap();
+        assert_eq!(decoded, headers);
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn dead_letter() {
+        let headers = Headers::new("send", "topic1", 7, ContentType::Text);
+        let dead = headers
+            .clone()
+            .dead_letter("failed", "receive", "trapped", 2);
+        assert_eq!(dead.topic, "failed");
+        assert_eq!(dead.user[DEAD_LETTER_TOPIC], "topic1");
+        assert_eq!(dead.user[DEAD_LETTER_ATTEMPTS], "2");
+        assert_eq!(dead.restore(), Some(headers.clone()));
+        assert_eq!(headers.restore(), None);
+    }
+}
@@ -1,21 +1,31 @@
 mod output;
 pub use output::Output;
 pub mod config;
+pub mod connection;
+pub mod header;
+pub mod log;
 
+#[cfg(feature = "wasm")]
+mod cache;
 #[cfg(feature = "wasm")]
 mod ptr;
 #[cfg(feature = "wasm")]
 mod wasm;
 #[cfg(feature = "wasm")]
+pub use cache::{CacheStatus, ModuleCache};
+#[cfg(feature = "wasm")]
 pub use ptr::U8WasmPtr;
 #[cfg(feature = "wasm")]
-pub use wasm::WasmHandler;
+pub use wasm::{
+    Counters, Inbound, Limit, LimitError, Session, SessionEvent, Settings, Status, WasmError,
+    WasmHandler,
+};
 
 // WebSocket module support
 #[cfg(all(feature = "ws", feature = "wasm"))]
 mod websocket;
 #[cfg(all(feature = "ws", feature = "wasm"))]
-pub use websocket::{wasm::WasmWSInstance, WebSocket};
+pub use websocket::{wasm::WasmWSInstance, Alert, Watchdog, WebSocket};
 
 #[cfg(feature = "wasm")]
 mod topic;
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::SystemTime;
+
+/// Topic reserved for log records of functions, those are collected by the pipeline host
+pub const LOG_TOPIC: &str = "grayarea.log";
+
+/// Log record written by function via `io.log` import
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogRecord {
+    pub function: String,
+    pub level: log::Level,
+    /// Index of message being processed, `None` when logged from main function
+    pub message: Option<u64>,
+    pub timestamp: SystemTime,
+    pub text: String,
+}
+
+impl LogRecord {
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Level as written by module, `1` for error to `5` for trace
+pub fn level_from_u32(level: u32) -> Option<log::Level> {
+    match level {
+        1 => Some(log::Level::Error),
+        2 => Some(log::Level::Warn),
+        3 => Some(log::Level::Info),
+        4 => Some(log::Level::Debug),
+        5 => Some(log::Level::Trace),
+        _ => None,
+    }
+}
+
+/// Record is rendered with the time function logged it, so that records collected
+/// from all the runtimes make one timed stream
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let timestamp = humantime::format_rfc3339_millis(self.timestamp);
+        match self.message {
+            Some(idx) => write!(f, "{} [message {}] {}", timestamp, idx, self.text),
+            None => write!(f, "{} {}", timestamp, self.text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogRecord;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn record_is_rendered_with_its_time() {
+        let record = LogRecord {
+            function: "f".to_string(),
+            level: log::Level::Info,
+            message: Some(3),
+            timestamp: UNIX_EPOCH + Duration::from_millis(1500),
+            text: "text".to_string(),
+        };
+        assert_eq!(
+            record.to_string(),
+            "1970-01-01T00:00:01.500Z [message 3] text"
+        );
+    }
+}
@@ -15,6 +15,10 @@ unsafe impl WasmExternType for U8WasmPtr {
     }
 }
 impl U8WasmPtr {
+    /// Offset in WASM module memory
+    pub fn offset(self) -> u32 {
+        self.offset
+    }
     /// Get a u8 slice
     /// # Safety
     /// Slice pointing to memory managed by WASM module, it is recommended to use it only
@@ -1,7 +1,12 @@
-use crate::{wasm, wasm::WasmHandle, U8WasmPtr, WasmHandler};
+use crate::header::{self, ContentType, Headers};
+use crate::log::LogRecord;
+use crate::{wasm::Settings, wasm::WasmHandle, Counters, Inbound, U8WasmPtr, WasmHandler};
 use crossbeam::channel;
 use ipc_orchestrator::message::Message;
-use wasmer_runtime::{func, imports, Ctx};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use wasmer_runtime::{func, imports, Ctx, ImportObject};
 
 type Receiver = channel::Receiver<Message>;
 
@@ -11,38 +16,19 @@ pub struct WasmTopicInstance {
 }
 
 impl WasmTopicInstance {
-    /// spawns WASM module in separate thread
-    /// TODO: This function is panicing on any exception
-    pub fn spawn(wasm_bytes: Vec<u8>, args: Vec<Vec<u8>>, topics: Vec<String>) -> Self {
+    /// spawns WASM module in separate thread delivering messages from `inbound`
+    pub fn spawn(
+        wasm_bytes: Vec<u8>,
+        settings: Settings,
+        topics: Vec<String>,
+        inbound: channel::Receiver<Vec<u8>>,
+    ) -> Self {
         let (tx, rx) = channel::bounded::<Message>(crate::CHANNEL_SIZE);
-        let topics_len = topics.len() as u32;
-
-        // prepare custom imports for wasm
-        let send_topic_message =
-            move |ctx: &mut Ctx, topic: u32, message_ptr: U8WasmPtr, len: u32| {
-                assert!(
-                    topic < topics_len,
-                    "send_topic_message: provided topic index {} out of bounds {}",
-                    topic,
-                    topics_len
-                );
-                let memory = ctx.memory(0);
-                let data = message_ptr
-                    .to_vec(memory, len)
-                    .expect("send_topic_message: failed to deref message");
-                let topic = topics[topic as usize].clone();
-                let msg = Message { topic, data };
-                tx.send(msg)
-                    .expect("send_topic_message: failed to send message");
-            };
-
-        let custom_imports = imports! {
-            "io" => {
-                "send_message_to_topic_idx" => func!(send_topic_message),
-            },
-        };
+        let producer = Producer::new(&settings.source, topics, tx);
+        let custom_imports = topic_imports(producer);
 
-        let inner = WasmHandler::spawn(wasm_bytes, args, Some(custom_imports), true);
+        let inbound = Some(Inbound::Messages(inbound));
+        let inner = WasmHandler::spawn(wasm_bytes, settings, Some(custom_imports), inbound);
 
         WasmTopicInstance { inner, rx }
     }
@@ -51,8 +37,16 @@ impl WasmTopicInstance {
         self.rx.clone()
     }
 
-    pub fn clone_sender(&self) -> Option<wasm::Sender> {
-        self.inner.clone_sender()
+    pub fn clone_log_receiver(&self) -> channel::Receiver<LogRecord> {
+        self.inner.clone_log_receiver()
+    }
+
+    pub fn clone_dead_letter_receiver(&self) -> Option<channel::Receiver<Vec<u8>>> {
+        self.inner.clone_dead_letter_receiver()
+    }
+
+    pub fn counters(&self) -> Arc<Counters> {
+        self.inner.counters()
     }
 }
 
@@ -61,3 +55,136 @@ impl Into<WasmHandle> for WasmTopicInstance {
         self.inner.into()
     }
 }
+
+/// Imports of the module sending messages to output topics of `producer`
+pub(crate) fn topic_imports(producer: Arc<Producer>) -> ImportObject {
+    // prepare custom imports for wasm
+    // errors are returned to WASM as a trap, which fails the function
+    let send = producer.clone();
+    let send_topic_message = move |ctx: &mut Ctx, topic: u32, message_ptr: U8WasmPtr, len: u32| {
+        let data = read(ctx, "send_topic_message", message_ptr, len)?;
+        send.send("send_topic_message", topic, &data, BTreeMap::new())
+    };
+    let send = producer.clone();
+    let send_topic_message_with_headers =
+        move |ctx: &mut Ctx,
+              topic: u32,
+              message_ptr: U8WasmPtr,
+              len: u32,
+              headers_ptr: U8WasmPtr,
+              headers_len: u32| {
+            let export = "send_topic_message_with_headers";
+            let data = read(ctx, export, message_ptr, len)?;
+            let user = read(ctx, export, headers_ptr, headers_len)?;
+            let user = bincode::deserialize(&user)
+                .map_err(|err| format!("{}: malformed headers: {}", export, err))?;
+            send.send(export, topic, &data, user)
+        };
+    // topic name is resolved to index in output topics, -1 if it is not declared
+    let resolve = producer.clone();
+    let topic_idx = move |ctx: &mut Ctx, name_ptr: U8WasmPtr, len: u32| {
+        let name = read(ctx, "topic_idx", name_ptr, len)?;
+        let idx = resolve
+            .topics
+            .iter()
+            .position(|topic| topic.as_bytes() == &name[..]);
+        Ok::<i32, String>(idx.map(|idx| idx as i32).unwrap_or(-1))
+    };
+    // declared topics are written as bincode list when they fit into provided buffer,
+    // size of the list is returned in any case
+    let declared = bincode::serialize(&producer.topics).expect("topics are serializable");
+    let output_topics = move |ctx: &mut Ctx, ptr: U8WasmPtr, capacity: u32| {
+        if declared.len() <= capacity as usize {
+            write(ctx, "output_topics", ptr, &declared)?;
+        }
+        Ok::<u32, String>(declared.len() as u32)
+    };
+
+    imports! {
+        "io" => {
+            "send_message_to_topic_idx" => func!(send_topic_message),
+            "send_message_to_topic_idx_with_headers" => func!(send_topic_message_with_headers),
+            "topic_idx" => func!(topic_idx),
+            "output_topics" => func!(output_topics),
+        },
+    }
+}
+
+/// Sends messages of the function to output topics wrapped into envelope with headers
+pub(crate) struct Producer {
+    /// Replica of the function, source of messages
+    source: String,
+    topics: Vec<String>,
+    seq: AtomicU64,
+    tx: channel::Sender<Message>,
+}
+
+impl Producer {
+    pub(crate) fn new(
+        source: &str,
+        topics: Vec<String>,
+        tx: channel::Sender<Message>,
+    ) -> Arc<Self> {
+        Arc::new(Producer {
+            source: source.to_string(),
+            topics,
+            seq: AtomicU64::new(0),
+            tx,
+        })
+    }
+
+    fn send(
+        &self,
+        export: &str,
+        topic: u32,
+        data: &[u8],
+        user: BTreeMap<String, String>,
+    ) -> Result<(), String> {
+        let topic = self.topics.get(topic as usize).ok_or_else(|| {
+            format!(
+                "{}: provided topic index {} out of bounds {}",
+                export,
+                topic,
+                self.topics.len()
+            )
+        })?;
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let mut headers = Headers::new(&self.source, topic, seq, ContentType::Binary);
+        headers.user = user;
+        let data = header::encode(&headers, data).map_err(|err| format!("{}: {}", export, err))?;
+        let msg = Message {
+            topic: topic.clone(),
+            data,
+        };
+        self.tx
+            .send(msg)
+            .map_err(|_| format!("{}: failed to send message", export))
+    }
+}
+
+fn read(ctx: &Ctx, export: &str, ptr: U8WasmPtr, len: u32) -> Result<Vec<u8>, String> {
+    ptr.to_vec(ctx.memory(0), len).ok_or_else(|| {
+        format!(
+            "{}: pointer {} of {} bytes out of memory bounds",
+            export,
+            ptr.offset(),
+            len
+        )
+    })
+}
+
+fn write(ctx: &Ctx, export: &str, ptr: U8WasmPtr, data: &[u8]) -> Result<(), String> {
+    // Should be safe as import runs in the thread of WASM module, which waits for it to return
+    let output = unsafe { ptr.get_mut_slice(ctx.memory(0), data.len() as u32) };
+    output
+        .ok_or_else(|| {
+            format!(
+                "{}: pointer {} of {} bytes out of memory bounds",
+                export,
+                ptr.offset(),
+                data.len()
+            )
+        })?
+        .copy_from_slice(data);
+    Ok(())
+}
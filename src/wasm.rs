@@ -1,75 +1,545 @@
 use super::U8WasmPtr;
-use anyhow::Result;
+use crate::cache::ModuleCache;
+use crate::config::{ErrorPolicy, Limits, ModuleConfig, OnError, Wasi};
+use crate::header;
+use crate::log::{level_from_u32, LogRecord};
+use anyhow::{anyhow, Result};
 use crossbeam::channel;
+use futures::executor::block_on;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, watch};
 use tokio::task::{spawn_blocking, JoinHandle};
-use wasmer_runtime::{instantiate, ImportObject, Instance};
+use wasmer_runtime::error::RuntimeError;
+use wasmer_runtime::{func, imports, Ctx, Func, ImportObject, Instance, Module};
 use wasmer_wasi::{generate_import_object_for_version, WasiVersion};
 
 pub type Sender = channel::Sender<Vec<u8>>;
+
+/// Latest version of host <-> module ABI supported by host
+///
+/// 1. messages are written to static buffer exported by module via `buffer_pointer`
+/// 2. buffer for every message is allocated via `grayarea_alloc` and freed via `grayarea_dealloc`
+/// 3. messages with headers are delivered via `on_envelope` and `on_envelopes` when exported
+/// 4. message handlers return acknowledgement, status in low 32 bits
+///    and index of the message within batch it applies to in high 32 bits
+pub const ABI_VERSION: u32 = 4;
+/// Size of the static message buffer exported by module via `buffer_pointer` in ABI version 1
+pub const MESSAGE_BUFFER_SIZE: usize = 1024 * 1024;
 pub type WasmHandle = JoinHandle<Result<()>>;
 
 pub struct WasmHandler {
     pub handle: WasmHandle,
-    txo: Option<Sender>,
+    logs: channel::Receiver<LogRecord>,
+    dead_letters: Option<channel::Receiver<Vec<u8>>>,
+    counters: Arc<Counters>,
 }
 
 pub struct WasmInstance {
     instance: Instance,
+    name: String,
+    limits: Limits,
+    on_error: OnError,
+    dead_letter: Option<DeadLetter>,
+    watch: Arc<Watch>,
+    counters: Arc<Counters>,
+    /// ABI version implemented by module
+    abi: u32,
+    /// Context of the instance holding metering points, taken once so that points are reset
+    /// while exports of the instance are borrowed
+    #[cfg(feature = "metering")]
+    ctx: *mut Ctx,
+}
+
+/// Messages delivered to the module once it is started
+pub enum Inbound {
+    /// Envelopes encoded by `header::encode`, delivered to message handlers
+    Messages(channel::Receiver<Vec<u8>>),
+    /// Connection events of the stream, delivered to handshaker hooks
+    Session(Session),
+}
+
+/// Connection session of the handshaker module
+///
+/// Module might export `on_connect`, `on_ws_message` and `on_disconnect` hooks,
+/// receiving url, frame of the stream and reason of disconnection respectively.
+/// Module which transforms the stream gets its frames via message handlers as well.
+pub struct Session {
+    /// Events are sent asynchronously, so that stream is read no faster than module handles it
+    pub events: mpsc::Receiver<SessionEvent>,
+    /// Whether frames are delivered to message handlers as `SessionEvent::Envelope`
+    pub transform: bool,
+    /// Set while main function runs, messages it sends are replayed on reconnect
+    pub handshake: Arc<AtomicBool>,
+    /// Whether module handles frames of the stream, sent once hooks of the module are known
+    pub frames: watch::Sender<Option<bool>>,
+}
+
+/// Event of the stream connection delivered to handshaker hooks
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// Connected to url
+    Connected(String),
+    /// Frame received from the stream
+    Message(Vec<u8>),
+    /// Connection lost for a reason
+    Disconnected(String),
+    /// Frame of the stream encoded by `header::encode`, delivered to message handlers
+    Envelope(Vec<u8>),
+}
+
+/// Status of message processing acknowledged by module
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Ok,
+    /// Message might be processed successfully later
+    Retry,
+    /// Message can't be processed
+    Reject,
+    /// Function can't continue
+    Fatal,
+    Unknown(u32),
+}
+
+impl From<u32> for Status {
+    fn from(code: u32) -> Self {
+        match code {
+            0 => Status::Ok,
+            1 => Status::Retry,
+            2 => Status::Reject,
+            3 => Status::Fatal,
+            code => Status::Unknown(code),
+        }
+    }
+}
+
+/// Counters of messages processed by function, shared by its module instances
+#[derive(Default, Debug)]
+pub struct Counters {
+    delivered: AtomicU64,
+    retried: AtomicU64,
+    skipped: AtomicU64,
+    dead_lettered: AtomicU64,
+}
+
+impl Counters {
+    /// Messages acknowledged by module
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::SeqCst)
+    }
+
+    /// Retries of messages acknowledged with retry status
+    pub fn retried(&self) -> u64 {
+        self.retried.load(Ordering::SeqCst)
+    }
+
+    /// Messages skipped after module failed to process them
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::SeqCst)
+    }
+
+    /// Messages published to dead letter topic
+    pub fn dead_lettered(&self) -> u64 {
+        self.dead_lettered.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "delivered {}, retried {}, skipped {}, dead-lettered {}",
+            self.delivered(),
+            self.retried(),
+            self.skipped(),
+            self.dead_lettered()
+        )
+    }
+}
+
+/// Function settings for the WASM instance, derived from module configuration
+pub struct Settings {
+    /// Function name, used to identify function in errors
+    pub name: String,
+    /// Name of the function replica, source of messages produced by the function
+    pub source: String,
+    pub wasi: Wasi,
+    pub limits: Limits,
+    pub on_error: OnError,
+    /// Topic messages module failed to process are published to
+    pub dead_letter: Option<String>,
+    /// Max number of messages delivered in one call into module
+    pub batch_size: usize,
+    /// Time to wait for the batch to fill up
+    pub batch_linger: Duration,
+    /// Counters of processed messages, pass the same counters to keep them between restarts
+    pub counters: Arc<Counters>,
+}
+
+impl Settings {
+    /// Resolves settings, fails if module configuration can't be satisfied by this runtime
+    pub fn new(config: &ModuleConfig) -> Result<Self> {
+        if cfg!(not(feature = "metering")) && config.limits.metering.is_some() {
+            return Err(anyhow!(
+                "limits.metering of {} requires grayarea built with metering feature",
+                config.name
+            ));
+        }
+        if config.batch_size == 0 {
+            return Err(anyhow!("batch_size of {} shall be at least 1", config.name));
+        }
+        Ok(Settings {
+            name: config.name.clone(),
+            source: config.name.clone(),
+            wasi: config.wasi()?,
+            limits: config.limits.clone(),
+            on_error: config.on_error.clone(),
+            dead_letter: config.dead_letter.clone(),
+            batch_size: config.batch_size,
+            batch_linger: config.batch_linger(),
+            counters: Arc::new(Counters::default()),
+        })
+    }
+}
+
+/// Execution limit violated by a function
+#[derive(Debug)]
+pub struct LimitError {
+    pub function: String,
+    /// Index of message being processed, `None` when limit was violated in main function
+    pub message: Option<u64>,
+    pub limit: Limit,
+}
+
+#[derive(Debug)]
+pub enum Limit {
+    Memory { pages: u32, max: u32 },
+    Timeout { elapsed: Duration, max: Duration },
+    Metering { max: u64 },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "function {} ", self.function)?;
+        match self.message {
+            Some(idx) => write!(f, "on message {} ", idx)?,
+            None => write!(f, "in main ")?,
+        }
+        match &self.limit {
+            Limit::Memory { pages, max } => {
+                write!(f, "uses {} memory pages over limit {}", pages, max)
+            }
+            Limit::Timeout { elapsed, max } => {
+                write!(f, "runs {:?} over time limit {:?}", elapsed, max)
+            }
+            Limit::Metering { max } => write!(f, "exceeded {} metering points", max),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+/// Failure of a function running WASM module
+#[derive(Debug)]
+pub enum WasmError {
+    /// Module could not be compiled or instantiated
+    Instantiate { function: String, error: String },
+    /// Module does not export required function
+    MissingExport { function: String, export: String },
+    /// Module trapped while running exported function
+    Trap {
+        function: String,
+        /// Index of message being processed, `None` when module trapped in main function
+        message: Option<u64>,
+        export: String,
+        error: String,
+    },
+    /// Message does not fit into the buffer provided by module
+    MessageTooLarge {
+        function: String,
+        message: u64,
+        size: usize,
+        capacity: usize,
+    },
+    /// Module failed to process message and on_error policy stops the function
+    Failed {
+        function: String,
+        message: u64,
+        status: Status,
+    },
+    /// Message received from pipeline could not be decoded
+    MalformedMessage {
+        function: String,
+        message: u64,
+        error: String,
+    },
+    /// Module provided pointer out of its memory bounds
+    BadPointer {
+        function: String,
+        message: Option<u64>,
+        offset: u32,
+        len: u32,
+    },
+    /// Module violated execution limits
+    Limit(LimitError),
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WasmError::Instantiate { function, error } => {
+                write!(
+                    f,
+                    "function {} failed to instantiate module: {}",
+                    function, error
+                )
+            }
+            WasmError::MissingExport { function, export } => {
+                write!(f, "function {} module does not export {}", function, export)
+            }
+            WasmError::Trap {
+                function,
+                message: Some(idx),
+                export,
+                error,
+            } => write!(
+                f,
+                "function {} trapped in {} on message {}: {}",
+                function, export, idx, error
+            ),
+            WasmError::Trap {
+                function,
+                message: None,
+                export,
+                error,
+            } => write!(f, "function {} trapped in {}: {}", function, export, error),
+            WasmError::MessageTooLarge {
+                function,
+                message,
+                size,
+                capacity,
+            } => write!(
+                f,
+                "function {} message {} of {} bytes does not fit into buffer of {} bytes",
+                function, message, size, capacity
+            ),
+            WasmError::Failed {
+                function,
+                message,
+                status,
+            } => write!(
+                f,
+                "function {} failed to process message {} with status {:?}",
+                function, message, status
+            ),
+            WasmError::MalformedMessage {
+                function,
+                message,
+                error,
+            } => write!(
+                f,
+                "function {} received malformed message {}: {}",
+                function, message, error
+            ),
+            WasmError::BadPointer {
+                function,
+                message,
+                offset,
+                len,
+            } => write!(
+                f,
+                "function {} on message {:?} provided pointer {} of {} bytes out of memory bounds",
+                function, message, offset, len
+            ),
+            WasmError::Limit(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for WasmError {}
+
+impl From<LimitError> for WasmError {
+    fn from(err: LimitError) -> Self {
+        WasmError::Limit(err)
+    }
+}
+
+/// Tracks calls into WASM module for the timeout watchdog
+struct Watch {
+    base: Instant,
+    /// Milliseconds since base when current call started plus one, 0 when idle
+    started: AtomicU64,
+    /// Index of message being processed plus one, 0 while running main function
+    message: AtomicU64,
+    /// Number of messages delivered in current call
+    count: AtomicU64,
+}
+
+impl Watch {
+    fn new() -> Self {
+        Watch {
+            base: Instant::now(),
+            started: AtomicU64::new(0),
+            message: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn enter(&self, message: Option<u64>, count: usize) {
+        self.message
+            .store(message.map(|idx| idx + 1).unwrap_or(0), Ordering::SeqCst);
+        self.count.store(count as u64, Ordering::SeqCst);
+        let started = self.base.elapsed().as_millis() as u64 + 1;
+        self.started.store(started, Ordering::SeqCst);
+    }
+
+    fn leave(&self) {
+        self.started.store(0, Ordering::SeqCst);
+    }
+
+    /// Index of message being processed, `None` while running main function
+    fn message(&self) -> Option<u64> {
+        self.message.load(Ordering::SeqCst).checked_sub(1)
+    }
+
+    /// Returns index of first message, number of messages and elapsed time of current call
+    fn current(&self) -> Option<(Option<u64>, u32, Duration)> {
+        let started = self.started.load(Ordering::SeqCst);
+        if started == 0 {
+            return None;
+        }
+        let message = self.message.load(Ordering::SeqCst).checked_sub(1);
+        let count = self.count.load(Ordering::SeqCst).max(1) as u32;
+        let elapsed = self.base.elapsed().as_millis() as u64 + 1 - started;
+        Some((message, count, Duration::from_millis(elapsed)))
+    }
 }
 
 impl WasmHandler {
     /// spawns WASM module in separate thread
-    /// TODO: This function is panicing on any exception
+    ///
+    /// Failure of the module is returned via handle as `WasmError`.
+    /// Messages or events from `inbound` are delivered to module until all the senders are gone.
+    ///
+    /// Module running over time limit fails the handle, but its thread can't be interrupted:
+    /// it keeps running in the blocking pool until module returns, only exit of the process stops it.
     pub fn spawn(
         wasm_bytes: Vec<u8>,
-        args: Vec<Vec<u8>>,
+        settings: Settings,
         custom_imports: Option<ImportObject>,
-        message_handler: bool,
+        mut inbound: Option<Inbound>,
     ) -> WasmHandler {
-        // TODO: add structured logging / standard loggin to wasm
-        // TODO: add WasiFs, handle stdin/stdout
+        // TODO: handle stdin/stdout
         // TODO: move base_imports to global cache to avoid loading bytes multiple times?
         // WASI imports
+        let Settings {
+            name,
+            wasi,
+            limits,
+            on_error,
+            dead_letter,
+            batch_size,
+            batch_linger,
+            counters,
+            ..
+        } = settings;
         let mut base_imports = generate_import_object_for_version(
             WasiVersion::Snapshot1,
-            args,
-            vec![],
-            vec![],
-            vec![],
+            wasi.args,
+            wasi.envs,
+            wasi.preopened,
+            wasi.mapped_dirs,
         );
         if let Some(imports) = custom_imports {
             base_imports.extend(imports);
         }
+        let watch = Arc::new(Watch::new());
+        let (log_tx, logs) = channel::bounded::<LogRecord>(crate::CHANNEL_SIZE);
+        base_imports.extend(log_imports(name.clone(), watch.clone(), log_tx));
 
-        // create communication channels from WASM runner to host app
-        let (mut txo, mut rxo) = (None, None);
-        if message_handler {
-            let (tx, rx) = channel::bounded::<Vec<u8>>(crate::CHANNEL_SIZE);
-            txo.replace(tx);
-            rxo.replace(rx);
-        }
+        // messages module failed to process are published via dead letter channel
+        let (dead_letter, dead_letters) = match dead_letter {
+            Some(topic) => {
+                let (tx, rx) = channel::bounded::<Vec<u8>>(crate::CHANNEL_SIZE);
+                let function = name.clone();
+                (
+                    Some(DeadLetter {
+                        function,
+                        topic,
+                        tx,
+                    }),
+                    Some(rx),
+                )
+            }
+            None => (None, None),
+        };
 
-        // TODO: when panic is hapenning in the thread it hangs the process
-        let handle = spawn_blocking(move || {
-            // TODO: add use of WASM compiler cache
-            let instance = WasmInstance {
-                instance: instantiate(&wasm_bytes[..], &base_imports)
-                    .expect("failed to instantiate module"),
-            };
-            instance.start();
-            if let Some(rx) = rxo {
-                for msg in rx.iter() {
-                    instance.on_message(&msg[..])
+        let timeouts = limits.start_timeout().is_some() || limits.message_timeout().is_some();
+        let watchdog = watchdog(watch.clone(), name.clone(), limits.clone());
+        let worker_counters = counters.clone();
+
+        let worker: WasmHandle = spawn_blocking(move || {
+            let module = compile(&name, &wasm_bytes[..], &limits)?;
+            let instance = module
+                .instantiate(&base_imports)
+                .map_err(|err| instantiate_error(&name, err))?;
+            let mut instance = WasmInstance::new(
+                instance,
+                &name,
+                &limits,
+                &on_error,
+                dead_letter,
+                watch,
+                worker_counters,
+            )?;
+            instance.check_memory(None)?;
+            let mut idx = 0;
+            match inbound.as_mut() {
+                Some(Inbound::Messages(rx)) => {
+                    instance.start()?;
+                    instance.run(rx, batch_size, batch_linger, &mut idx)?
                 }
+                Some(Inbound::Session(session)) => instance.session(session, &mut idx)?,
+                None => instance.start()?,
             }
             Ok(())
         });
 
-        WasmHandler { handle, txo }
+        // Thread stuck in WASM code can't be stopped, watchdog fails the handle instead
+        let handle = if timeouts {
+            tokio::spawn(async move {
+                tokio::select! {
+                    res = worker => res?,
+                    res = watchdog => res,
+                }
+            })
+        } else {
+            worker
+        };
+
+        WasmHandler {
+            handle,
+            logs,
+            dead_letters,
+            counters,
+        }
+    }
+
+    /// Counters of messages processed by module
+    pub fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
     }
 
-    pub fn clone_sender(&self) -> Option<Sender> {
-        self.txo.clone()
+    /// Log records written by module via `io.log`
+    pub fn clone_log_receiver(&self) -> channel::Receiver<LogRecord> {
+        self.logs.clone()
+    }
+
+    /// Envelopes of messages module failed to process, when dead letter topic is configured
+    pub fn clone_dead_letter_receiver(&self) -> Option<channel::Receiver<Vec<u8>>> {
+        self.dead_letters.clone()
     }
 }
 
@@ -79,54 +549,995 @@ impl Into<WasmHandle> for WasmHandler {
     }
 }
 
+/// `io.log` import, module passes level and utf8 text of the record.
+/// Record gets function name, index of message being processed and timestamp.
+fn log_imports(
+    function: String,
+    watch: Arc<Watch>,
+    tx: channel::Sender<LogRecord>,
+) -> ImportObject {
+    let log = move |ctx: &mut Ctx, level: u32, text_ptr: U8WasmPtr, len: u32| {
+        let level = level_from_u32(level).ok_or_else(|| format!("log: unknown level {}", level))?;
+        let text = text_ptr.to_vec(ctx.memory(0), len).ok_or_else(|| {
+            format!(
+                "log: pointer {} of {} bytes out of memory bounds",
+                text_ptr.offset(),
+                len
+            )
+        })?;
+        let record = LogRecord {
+            function: function.clone(),
+            level,
+            message: watch.message(),
+            timestamp: SystemTime::now(),
+            text: String::from_utf8_lossy(&text).into_owned(),
+        };
+        // records are dropped when collector falls behind rather than blocking the module
+        let _ = tx.try_send(record);
+        Ok::<(), String>(())
+    };
+    imports! {
+        "io" => {
+            "log" => func!(log),
+        },
+    }
+}
+
+/// Fails when call into WASM module runs longer than configured timeout
+async fn watchdog(watch: Arc<Watch>, function: String, limits: Limits) -> Result<()> {
+    let (start, message) = (limits.start_timeout(), limits.message_timeout());
+    let period = start
+        .into_iter()
+        .chain(message)
+        .min()
+        .map(|timeout| timeout / 4)
+        .unwrap_or_else(|| Duration::from_secs(1))
+        .max(Duration::from_millis(1));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Some((idx, count, elapsed)) = watch.current() {
+            // batch gets message timeout for every message in it
+            let max = if idx.is_some() {
+                message.map(|max| max * count)
+            } else {
+                start
+            };
+            match max {
+                Some(max) if elapsed > max => {
+                    return Err(WasmError::from(LimitError {
+                        function,
+                        message: idx,
+                        limit: Limit::Timeout { elapsed, max },
+                    })
+                    .into())
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "metering"))]
+fn compile(function: &str, wasm_bytes: &[u8], _limits: &Limits) -> Result<Module, WasmError> {
+    cached_compile(function, wasm_bytes)
+}
+
+/// Metered module is compiled with singlepass backend and metering middleware on every start,
+/// it skips compiled modules cache as compiled code depends on metering limit
+#[cfg(feature = "metering")]
+fn compile(function: &str, wasm_bytes: &[u8], limits: &Limits) -> Result<Module, WasmError> {
+    use wasmer_middleware_common::metering::Metering;
+    use wasmer_runtime::compile_with;
+    use wasmer_runtime_core::codegen::{MiddlewareChain, StreamingCompiler};
+    use wasmer_singlepass_backend::ModuleCodeGenerator as SinglePassMCG;
+
+    match limits.metering {
+        Some(points) => {
+            let compiler: StreamingCompiler<SinglePassMCG, _, _, _, _> =
+                StreamingCompiler::new(move || {
+                    let mut chain = MiddlewareChain::new();
+                    chain.push(Metering::new(points));
+                    chain
+                });
+            compile_with(wasm_bytes, &compiler).map_err(|err| instantiate_error(function, err))
+        }
+        None => cached_compile(function, wasm_bytes),
+    }
+}
+
+/// Compiles module via compiled modules cache, failure of the cache only costs compilation time
+fn cached_compile(function: &str, wasm_bytes: &[u8]) -> Result<Module, WasmError> {
+    match ModuleCache::open().and_then(|mut cache| cache.load_or_compile(wasm_bytes)) {
+        Ok((module, _)) => Ok(module),
+        Err(err) => {
+            log::warn!(
+                "function {} compiled modules cache failed: {}",
+                function,
+                err
+            );
+            wasmer_runtime::compile(wasm_bytes).map_err(|err| instantiate_error(function, err))
+        }
+    }
+}
+
+fn instantiate_error(function: &str, err: impl fmt::Display) -> WasmError {
+    WasmError::Instantiate {
+        function: function.to_string(),
+        error: err.to_string(),
+    }
+}
+
 impl WasmInstance {
-    /// Start WASM process panics on any exceptions.
+    fn new(
+        instance: Instance,
+        name: &str,
+        limits: &Limits,
+        on_error: &OnError,
+        dead_letter: Option<DeadLetter>,
+        watch: Arc<Watch>,
+        counters: Arc<Counters>,
+    ) -> Result<Self, WasmError> {
+        // modules built before versioning do not export ABI version
+        let abi = match instance.func::<(), u32>("grayarea_abi_version") {
+            Ok(version) => version.call().map_err(|err| WasmError::Trap {
+                function: name.to_string(),
+                message: None,
+                export: "grayarea_abi_version".to_string(),
+                error: err.to_string(),
+            })?,
+            Err(_) => 1,
+        };
+        if abi > ABI_VERSION {
+            return Err(WasmError::Instantiate {
+                function: name.to_string(),
+                error: format!(
+                    "module ABI version {} is newer than supported {}",
+                    abi, ABI_VERSION
+                ),
+            });
+        }
+        #[cfg(feature = "metering")]
+        let mut instance = instance;
+        #[cfg(feature = "metering")]
+        let ctx = instance.context_mut() as *mut Ctx;
+        Ok(WasmInstance {
+            instance,
+            name: name.to_string(),
+            limits: limits.clone(),
+            on_error: on_error.clone(),
+            dead_letter,
+            watch,
+            counters,
+            abi,
+            #[cfg(feature = "metering")]
+            ctx,
+        })
+    }
+
+    /// Runs main function of WASM module.
     /// It is run in a WASM thread
-    pub fn start(&self) {
+    pub fn start(&self) -> Result<(), WasmError> {
+        self.reset_metering();
         // get a reference to the function "plugin_entrypoint"
         let entry_point = self
             .instance
             .func::<(), ()>("_start")
-            .expect("failed to find entry point in wasm module");
+            .map_err(|_| self.missing_export("_start"))?;
         // call the "entry_point" function in WebAssembly
-        entry_point.call().expect("failed to execute module")
+        self.watch.enter(None, 1);
+        let res = entry_point.call();
+        self.watch.leave();
+        self.check_limits(None)?;
+        res.map_err(|err| self.trap(None, "_start", err))
     }
 
-    /// Message handler for messages sent from a WASM
-    /// Panics on exceptions
+    /// Delivers messages from the channel until all the senders are gone,
+    /// `idx` is index of the next message and is advanced as messages are delivered.
     /// It runs in a WASM thread
-    pub fn on_message(&self, msg: &[u8]) {
-        // get a reference to the function "plugin_entrypoint"
-        let memory = self.instance.context().memory(0);
-        let buffer_pointer = self
-            .instance
-            .func::<(), U8WasmPtr>("buffer_pointer")
-            .expect("failed to find buffer_pointer in wasm module");
-        let buffer = buffer_pointer
-            .call()
-            .expect("failed to acquire memory buffer from wasm module");
-        if msg.len() > 1024 * 1024 {
-            panic!(format!(
-                "Received message {} does not fit into buffer",
-                msg.len()
-            ))
+    pub fn run(
+        &mut self,
+        rx: &channel::Receiver<Vec<u8>>,
+        batch_size: usize,
+        batch_linger: Duration,
+        idx: &mut u64,
+    ) -> Result<(), WasmError> {
+        let exports = Exports::new(self)?;
+        while let Some(batch) = receive_batch(rx, batch_size, batch_linger) {
+            self.reset_metering();
+            self.deliver(&exports, *idx, &batch)?;
+            *idx += batch.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Runs main function and delivers connection events to hooks until all the senders are gone,
+    /// `idx` is index of the next event and is advanced as events are delivered.
+    /// It runs in a WASM thread
+    pub fn session(&self, session: &mut Session, idx: &mut u64) -> Result<(), WasmError> {
+        let hooks = Hooks::new(self, session.transform)?;
+        // receivers are gone once runtime stopped the function
+        let _ = session.frames.broadcast(Some(hooks.on_ws_message.is_some()));
+        session.handshake.store(true, Ordering::SeqCst);
+        let res = self.start();
+        session.handshake.store(false, Ordering::SeqCst);
+        res?;
+        while let Some(event) = block_on(session.events.recv()) {
+            self.reset_metering();
+            self.notify(&hooks, *idx, &event)?;
+            *idx += 1;
+        }
+        Ok(())
+    }
+
+    /// Calls hook of the event if module exports it, event data is copied into module memory
+    fn notify(&self, hooks: &Hooks, idx: u64, event: &SessionEvent) -> Result<(), WasmError> {
+        let (hook, export, data) = match event {
+            SessionEvent::Envelope(envelope) => {
+                return match hooks.handlers.as_ref() {
+                    Some(exports) => self.deliver(exports, idx, std::slice::from_ref(envelope)),
+                    None => Ok(()),
+                };
+            }
+            SessionEvent::Connected(url) => (&hooks.on_connect, "on_connect", url.as_bytes()),
+            SessionEvent::Message(data) => (&hooks.on_ws_message, "on_ws_message", &data[..]),
+            SessionEvent::Disconnected(reason) => {
+                (&hooks.on_disconnect, "on_disconnect", reason.as_bytes())
+            }
+        };
+        // buffer is looked up whenever module exports any of hooks
+        let (hook, buffer) = match (hook, hooks.buffer.as_ref()) {
+            (Some(hook), Some(buffer)) => (hook, buffer),
+            _ => return Ok(()),
+        };
+        let ptr = self.write_message(buffer, idx, data)?;
+        self.watch.enter(Some(idx), 1);
+        let res = hook.call(ptr, data.len() as i32);
+        self.watch.leave();
+        self.check_limits(Some(idx))?;
+        res.map_err(|err| self.trap(Some(idx), export, err))?;
+        self.free_message(buffer, idx, ptr, data.len())
+    }
+
+    /// Delivers batch of messages starting from message `idx`.
+    /// Messages are envelopes with headers, modules not exporting `on_envelope` get data only.
+    fn deliver(&self, exports: &Exports, idx: u64, batch: &[Vec<u8>]) -> Result<(), WasmError> {
+        match exports.on_envelope.as_ref() {
+            Some(on_envelope) => self.deliver_with(
+                exports,
+                (on_envelope, "on_envelope"),
+                exports.on_envelopes.as_ref().map(|f| (f, "on_envelopes")),
+                idx,
+                batch,
+                batch,
+            ),
+            None => {
+                let data = batch
+                    .iter()
+                    .enumerate()
+                    .map(|(i, envelope)| {
+                        header::decode(envelope)
+                            .map(|(_, data)| data)
+                            .map_err(|err| WasmError::MalformedMessage {
+                                function: self.name.clone(),
+                                message: idx + i as u64,
+                                error: err.to_string(),
+                            })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.deliver_with(
+                    exports,
+                    (&exports.on_message, "on_message"),
+                    exports.on_messages.as_ref().map(|f| (f, "on_messages")),
+                    idx,
+                    &data,
+                    batch,
+                )
+            }
+        }
+    }
+
+    /// Delivers batch via single call of batch handler if module exports it,
+    /// otherwise calls message handler for every message.
+    /// Message module failed to process is handled according to on_error policy,
+    /// `envelopes` of the batch are published to dead letter topic when module traps.
+    fn deliver_with<M: AsRef<[u8]>>(
+        &self,
+        exports: &Exports,
+        handler: (&Handler, &str),
+        batch_handler: Option<(&Handler, &str)>,
+        idx: u64,
+        batch: &[M],
+        envelopes: &[Vec<u8>],
+    ) -> Result<(), WasmError> {
+        let mut start = 0;
+        while start < batch.len() {
+            let rest = &batch[start..];
+            let first = idx + start as u64;
+            let (res, count) = match batch_handler {
+                Some((batch_handler, export)) if rest.len() > 1 => {
+                    let packed = pack_batch(rest);
+                    let res = self.call(exports, batch_handler, export, first, rest.len(), &packed);
+                    (res, rest.len())
+                }
+                _ => {
+                    let res = self.call(exports, handler.0, handler.1, first, 1, rest[0].as_ref());
+                    (res, 1)
+                }
+            };
+            // messages of the failed call are lost together with the instance
+            let ack = res.map_err(|err| {
+                for envelope in envelopes[start..start + count].iter() {
+                    self.dead_letter(envelope, &err, 1);
+                }
+                err
+            })?;
+            if ack.status == Status::Ok {
+                self.counters
+                    .delivered
+                    .fetch_add(count as u64, Ordering::SeqCst);
+                start += count;
+                continue;
+            }
+            // messages before the failed one were processed
+            let failed = ack.index.min(count - 1);
+            self.counters
+                .delivered
+                .fetch_add(failed as u64, Ordering::SeqCst);
+            let idx = first + failed as u64;
+            let envelope = &envelopes[start + failed];
+            self.recover(
+                exports,
+                handler,
+                idx,
+                rest[failed].as_ref(),
+                envelope,
+                ack.status,
+            )?;
+            start += failed + 1;
+        }
+        Ok(())
+    }
+
+    /// Applies on_error policy to the message module failed to process,
+    /// message which is not going to be processed is published to dead letter topic
+    fn recover(
+        &self,
+        exports: &Exports,
+        (handler, export): (&Handler, &str),
+        idx: u64,
+        msg: &[u8],
+        envelope: &[u8],
+        mut status: Status,
+    ) -> Result<(), WasmError> {
+        let mut retries = 0;
+        loop {
+            let failed = WasmError::Failed {
+                function: self.name.clone(),
+                message: idx,
+                status,
+            };
+            let retry = match (status, self.on_error.policy) {
+                (Status::Fatal, _) | (Status::Unknown(_), _) | (_, ErrorPolicy::Stop) => {
+                    self.dead_letter(envelope, &failed, retries + 1);
+                    return Err(failed);
+                }
+                (Status::Retry, ErrorPolicy::Retry) => retries < self.on_error.max_retries,
+                // rejected messages and ones out of retries are skipped
+                _ => false,
+            };
+            if !retry {
+                self.dead_letter(envelope, &failed, retries + 1);
+                self.counters.skipped.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            }
+            std::thread::sleep(self.on_error.backoff(retries));
+            retries += 1;
+            self.counters.retried.fetch_add(1, Ordering::SeqCst);
+            status = self
+                .call(exports, handler, export, idx, 1, msg)
+                .map_err(|err| {
+                    self.dead_letter(envelope, &err, retries + 1);
+                    err
+                })?
+                .status;
+            if status == Status::Ok {
+                self.counters.delivered.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Publishes message to dead letter topic when it is configured
+    fn dead_letter(&self, envelope: &[u8], error: &WasmError, attempts: u32) {
+        if let Some(dead_letter) = self.dead_letter.as_ref() {
+            if dead_letter.publish(envelope, error, attempts) {
+                self.counters.dead_lettered.fetch_add(1, Ordering::SeqCst);
+            }
         }
+    }
+
+    /// Calls message handler export with data copied into module memory
+    fn call(
+        &self,
+        exports: &Exports,
+        handler: &Handler,
+        export: &str,
+        idx: u64,
+        count: usize,
+        data: &[u8],
+    ) -> Result<Ack, WasmError> {
+        let buffer = self.write_message(&exports.buffer, idx, data)?;
+        self.watch.enter(Some(idx), count);
+        let res = handler.call(buffer, data.len() as i32);
+        self.watch.leave();
+        self.check_limits(Some(idx))?;
+        let ack = res.map_err(|err| self.trap(Some(idx), export, err))?;
+        self.free_message(&exports.buffer, idx, buffer, data.len())?;
+        Ok(ack)
+    }
+
+    /// Copies message into buffer in module memory
+    fn write_message(&self, buffer: &Buffer, idx: u64, msg: &[u8]) -> Result<U8WasmPtr, WasmError> {
+        let buffer = match buffer {
+            Buffer::Allocated { alloc, .. } => alloc
+                .call(msg.len() as u32)
+                .map_err(|err| self.trap(Some(idx), "grayarea_alloc", err))?,
+            Buffer::Static(buffer_pointer) => {
+                if msg.len() > MESSAGE_BUFFER_SIZE {
+                    return Err(WasmError::MessageTooLarge {
+                        function: self.name.clone(),
+                        message: idx,
+                        size: msg.len(),
+                        capacity: MESSAGE_BUFFER_SIZE,
+                    });
+                }
+                buffer_pointer
+                    .call()
+                    .map_err(|err| self.trap(Some(idx), "buffer_pointer", err))?
+            }
+        };
+        let memory = self.instance.context().memory(0);
         // Should be safe as it works in the same thread with WASM and
         // does not give control back to WASM module which manages this memory
         unsafe {
             let output = buffer
                 .get_mut_slice(memory, msg.len() as u32)
-                .expect("failed to deref buffer as mutable u8 buffer");
+                .ok_or_else(|| WasmError::BadPointer {
+                    function: self.name.clone(),
+                    message: Some(idx),
+                    offset: buffer.offset(),
+                    len: msg.len() as u32,
+                })?;
             output.copy_from_slice(msg);
         }
+        Ok(buffer)
+    }
 
-        let on_message = self
-            .instance
-            .func::<(U8WasmPtr, i32), ()>("on_message")
-            .expect("failed to find on_message in wasm module");
-        // call the "entry_point" function in WebAssembly
-        on_message
-            .call(buffer, msg.len() as i32)
-            .expect("failed to call module's on_message")
+    /// Frees buffer allocated by `write_message`
+    fn free_message(
+        &self,
+        buffer: &Buffer,
+        idx: u64,
+        ptr: U8WasmPtr,
+        len: usize,
+    ) -> Result<(), WasmError> {
+        match buffer {
+            Buffer::Allocated { dealloc, .. } => dealloc
+                .call(ptr, len as u32)
+                .map_err(|err| self.trap(Some(idx), "grayarea_dealloc", err)),
+            Buffer::Static(_) => Ok(()),
+        }
+    }
+
+    fn missing_export(&self, export: &str) -> WasmError {
+        WasmError::MissingExport {
+            function: self.name.clone(),
+            export: export.to_string(),
+        }
+    }
+
+    fn trap(&self, message: Option<u64>, export: &str, err: impl fmt::Display) -> WasmError {
+        WasmError::Trap {
+            function: self.name.clone(),
+            message,
+            export: export.to_string(),
+            error: err.to_string(),
+        }
+    }
+
+    fn check_limits(&self, message: Option<u64>) -> Result<(), LimitError> {
+        self.check_metering(message)?;
+        self.check_memory(message)
+    }
+
+    fn check_memory(&self, message: Option<u64>) -> Result<(), LimitError> {
+        let pages = self.instance.context().memory(0).size().0;
+        match self.limits.max_memory_pages {
+            Some(max) if pages > max => Err(self.error(message, Limit::Memory { pages, max })),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "metering")]
+    fn reset_metering(&self) {
+        if self.limits.metering.is_some() {
+            // Safety: context lives as long as the instance owned by self, and it is not borrowed
+            // by the host in between calls into the module, all of those run in this thread
+            unsafe { wasmer_middleware_common::metering::set_points_used_ctx(&mut *self.ctx, 0) }
+        }
+    }
+
+    #[cfg(feature = "metering")]
+    fn check_metering(&self, message: Option<u64>) -> Result<(), LimitError> {
+        use wasmer_middleware_common::metering::get_points_used;
+        match self.limits.metering {
+            Some(max) if get_points_used(&self.instance) >= max => {
+                Err(self.error(message, Limit::Metering { max }))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(not(feature = "metering"))]
+    fn reset_metering(&self) {}
+
+    #[cfg(not(feature = "metering"))]
+    fn check_metering(&self, _message: Option<u64>) -> Result<(), LimitError> {
+        Ok(())
+    }
+
+    fn error(&self, message: Option<u64>, limit: Limit) -> LimitError {
+        LimitError {
+            function: self.name.clone(),
+            message,
+            limit,
+        }
+    }
+}
+
+/// Dead letter topic of the function
+struct DeadLetter {
+    function: String,
+    topic: String,
+    tx: Sender,
+}
+
+impl DeadLetter {
+    /// Publishes envelope with dead letter headers, returns whether message was published
+    fn publish(&self, envelope: &[u8], error: &WasmError, attempts: u32) -> bool {
+        let res = header::decode(envelope).and_then(|(headers, data)| {
+            let error = error.to_string();
+            let headers = headers.dead_letter(&self.topic, &self.function, &error, attempts);
+            header::encode(&headers, data)
+        });
+        match res {
+            Ok(msg) => self.tx.send(msg).is_ok(),
+            Err(err) => {
+                log::error!(
+                    "function {} could not publish message to dead letter topic {}: {}",
+                    self.function,
+                    self.topic,
+                    err
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Handles of functions exported by module, looked up once instead of on every message
+struct Exports<'a> {
+    on_message: Handler<'a>,
+    /// Optional handler of packed batch of messages
+    on_messages: Option<Handler<'a>>,
+    /// Optional handlers of messages with headers
+    on_envelope: Option<Handler<'a>>,
+    on_envelopes: Option<Handler<'a>>,
+    buffer: Buffer<'a>,
+}
+
+/// Exported function receiving pointer and length of message or batch,
+/// it acknowledges messages since ABI version 4
+enum Handler<'a> {
+    Unacked(Func<'a, (U8WasmPtr, i32)>),
+    Acked(Func<'a, (U8WasmPtr, i32), i64>),
+}
+
+impl<'a> Handler<'a> {
+    fn lookup(wasm: &'a WasmInstance, export: &str) -> Option<Self> {
+        if wasm.abi >= 4 {
+            wasm.instance.func(export).ok().map(Handler::Acked)
+        } else {
+            wasm.instance.func(export).ok().map(Handler::Unacked)
+        }
+    }
+
+    fn call(&self, ptr: U8WasmPtr, len: i32) -> Result<Ack, RuntimeError> {
+        match self {
+            Handler::Unacked(handler) => handler.call(ptr, len).map(|()| Ack {
+                status: Status::Ok,
+                index: 0,
+            }),
+            Handler::Acked(handler) => handler.call(ptr, len).map(Ack::from),
+        }
+    }
+}
+
+/// Acknowledgement of message or batch returned by module
+#[derive(Debug, PartialEq)]
+struct Ack {
+    status: Status,
+    /// Index of the message within batch status applies to
+    index: usize,
+}
+
+impl From<i64> for Ack {
+    /// Unpacks acknowledgement of ABI version 4: status in low 32 bits
+    /// and index of the message within batch in high 32 bits
+    fn from(ack: i64) -> Self {
+        Ack {
+            status: Status::from(ack as u32),
+            index: (ack as u64 >> 32) as usize,
+        }
+    }
+}
+
+/// Message buffer provided by module according to its ABI version
+enum Buffer<'a> {
+    Static(Func<'a, (), U8WasmPtr>),
+    Allocated {
+        alloc: Func<'a, u32, U8WasmPtr>,
+        dealloc: Func<'a, (U8WasmPtr, u32)>,
+    },
+}
+
+impl<'a> Buffer<'a> {
+    fn new(wasm: &'a WasmInstance) -> Result<Self, WasmError> {
+        let instance = &wasm.instance;
+        if wasm.abi >= 2 {
+            Ok(Buffer::Allocated {
+                alloc: instance
+                    .func("grayarea_alloc")
+                    .map_err(|_| wasm.missing_export("grayarea_alloc"))?,
+                dealloc: instance
+                    .func("grayarea_dealloc")
+                    .map_err(|_| wasm.missing_export("grayarea_dealloc"))?,
+            })
+        } else {
+            Ok(Buffer::Static(
+                instance
+                    .func("buffer_pointer")
+                    .map_err(|_| wasm.missing_export("buffer_pointer"))?,
+            ))
+        }
+    }
+}
+
+impl<'a> Exports<'a> {
+    fn new(wasm: &'a WasmInstance) -> Result<Self, WasmError> {
+        Ok(Exports {
+            on_message: Handler::lookup(wasm, "on_message")
+                .ok_or_else(|| wasm.missing_export("on_message"))?,
+            on_messages: Handler::lookup(wasm, "on_messages"),
+            on_envelope: Handler::lookup(wasm, "on_envelope"),
+            on_envelopes: Handler::lookup(wasm, "on_envelopes"),
+            buffer: Buffer::new(wasm)?,
+        })
+    }
+}
+
+/// Optional connection hooks exported by handshaker module
+struct Hooks<'a> {
+    on_connect: Option<Func<'a, (U8WasmPtr, i32)>>,
+    on_ws_message: Option<Func<'a, (U8WasmPtr, i32)>>,
+    on_disconnect: Option<Func<'a, (U8WasmPtr, i32)>>,
+    /// Message handlers of module transforming the stream
+    handlers: Option<Exports<'a>>,
+    /// Buffer hooks receive data in, required only when module exports any of hooks
+    buffer: Option<Buffer<'a>>,
+}
+
+impl<'a> Hooks<'a> {
+    fn new(wasm: &'a WasmInstance, transform: bool) -> Result<Self, WasmError> {
+        let handlers = if transform {
+            Some(Exports::new(wasm)?)
+        } else {
+            None
+        };
+        let on_connect = wasm.instance.func("on_connect").ok();
+        let on_ws_message = wasm.instance.func("on_ws_message").ok();
+        let on_disconnect = wasm.instance.func("on_disconnect").ok();
+        let buffer = if on_connect.is_some() || on_ws_message.is_some() || on_disconnect.is_some() {
+            Some(Buffer::new(wasm)?)
+        } else {
+            None
+        };
+        Ok(Hooks {
+            on_connect,
+            on_ws_message,
+            on_disconnect,
+            handlers,
+            buffer,
+        })
+    }
+}
+
+/// Packs messages as frames of u32 little endian length followed by message bytes
+fn pack_batch<M: AsRef<[u8]>>(batch: &[M]) -> Vec<u8> {
+    let size = batch.iter().map(|msg| msg.as_ref().len() + 4).sum();
+    let mut packed = Vec::with_capacity(size);
+    for msg in batch.iter().map(AsRef::as_ref) {
+        packed.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+        packed.extend_from_slice(msg);
+    }
+    packed
+}
+
+/// Waits for the next message, then collects up to `size` messages
+/// arriving within `linger`. Returns `None` when all the senders are gone.
+fn receive_batch(
+    rx: &channel::Receiver<Vec<u8>>,
+    size: usize,
+    linger: Duration,
+) -> Option<Vec<Vec<u8>>> {
+    let mut batch = vec![rx.recv().ok()?];
+    let deadline = Instant::now() + linger;
+    while batch.len() < size {
+        let msg = match rx.try_recv() {
+            Ok(msg) => msg,
+            Err(_) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                match rx.recv_timeout(deadline - now) {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                }
+            }
+        };
+        batch.push(msg);
+    }
+    Some(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        pack_batch, receive_batch, Ack, Inbound, Limit, LimitError, Session, SessionEvent,
+        Settings, Status, WasmError, WasmHandler,
+    };
+    use crate::config::{Limits, OnError, Wasi};
+    use crate::header::{self, ContentType, Headers};
+    use crossbeam::channel;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, watch};
+
+    /// Module with one page of exported memory and `_start` of given code:
+    /// (module (memory (export "memory") 1) (func (export "_start") <code>))
+    fn module(code: &[u8]) -> Vec<u8> {
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        // type () -> (), function of the type, memory of 1 page
+        wasm.extend_from_slice(&[1, 4, 1, 0x60, 0, 0, 3, 2, 1, 0, 5, 3, 1, 0, 1]);
+        wasm.extend_from_slice(&[7, 19, 2, 6]);
+        wasm.extend_from_slice(b"memory");
+        wasm.extend_from_slice(&[2, 0, 6]);
+        wasm.extend_from_slice(b"_start");
+        wasm.extend_from_slice(&[0, 0]);
+        // code section of one body without locals
+        let body = code.len() as u8 + 2;
+        wasm.extend_from_slice(&[10, body + 2, 1, body, 0]);
+        wasm.extend_from_slice(code);
+        wasm.push(0x0b);
+        wasm
+    }
+
+    fn settings(limits: Limits) -> Settings {
+        Settings {
+            name: "limited".to_string(),
+            source: "limited".to_string(),
+            wasi: Wasi {
+                args: Vec::new(),
+                envs: Vec::new(),
+                preopened: Vec::new(),
+                mapped_dirs: Vec::new(),
+            },
+            limits,
+            on_error: OnError::default(),
+            dead_letter: None,
+            batch_size: 1,
+            batch_linger: Duration::from_millis(0),
+            counters: Default::default(),
+        }
+    }
+
+    async fn run(code: &[u8], limits: Limits) -> Option<Limit> {
+        let handler = WasmHandler::spawn(module(code), settings(limits), None, None);
+        let err = handler.handle.await.unwrap().unwrap_err();
+        match err.downcast::<WasmError>() {
+            Ok(WasmError::Limit(LimitError { limit, .. })) => Some(limit),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_limit() {
+        // i32.const 10, memory.grow, drop
+        let grow = [0x41, 10, 0x40, 0, 0x1a];
+        let limits = Limits {
+            max_memory_pages: Some(4),
+            ..Limits::default()
+        };
+        match run(&grow, limits).await {
+            Some(Limit::Memory { pages, max }) => assert_eq!((pages, max), (11, 4)),
+            limit => panic!("unexpected {:?}", limit),
+        }
+        let handler = WasmHandler::spawn(module(&grow), settings(Limits::default()), None, None);
+        assert!(handler.handle.await.unwrap().is_ok());
+    }
+
+    /// Module handling message in long loop, long enough to run over time limit
+    /// but returning eventually, as thread stuck in module can't be stopped:
+    /// (memory (export "memory") 1)
+    /// (func (export "_start"))
+    /// (func (export "on_message") (param i32 i32) (local i32)
+    ///   (local.set 2 (i32.const 0x40000000))
+    ///   (loop (br_if 0 (local.tee 2 (i32.sub (local.get 2) (i32.const 1))))))
+    /// (func (export "buffer_pointer") (result i32) (i32.const 0))
+    const SPIN_ON_MESSAGE: &[u8] = b"\0asm\x01\0\0\0\
+        \x01\x0d\x03\x60\0\0\x60\x02\x7f\x7f\0\x60\0\x01\x7f\
+        \x03\x04\x03\0\x01\x02\
+        \x05\x03\x01\0\x01\
+        \x07\x31\x04\x06memory\x02\0\x06_start\0\0\x0aon_message\0\x01\
+        \x0ebuffer_pointer\0\x02\
+        \x0a\x22\x03\x02\0\x0b\
+        \x18\x01\x01\x7f\x41\x80\x80\x80\x80\x04\x21\x02\
+        \x03\x40\x20\x02\x41\x01\x6b\x22\x02\x0d\0\x0b\x0b\
+        \x04\0\x41\0\x0b";
+
+    #[tokio::test]
+    async fn message_timeout() {
+        let limits = Limits {
+            message_timeout_ms: Some(50),
+            ..Limits::default()
+        };
+        let (tx, rx) = channel::unbounded();
+        let headers = Headers::new("source", "topic", 0, ContentType::Binary);
+        tx.send(header::encode(&headers, &[0]).unwrap()).unwrap();
+        let inbound = Some(Inbound::Messages(rx));
+        let handler =
+            WasmHandler::spawn(SPIN_ON_MESSAGE.to_vec(), settings(limits), None, inbound);
+        let err = handler.handle.await.unwrap().unwrap_err();
+        match err.downcast::<WasmError>() {
+            Ok(WasmError::Limit(LimitError {
+                function,
+                message,
+                limit: Limit::Timeout { max, .. },
+            })) => {
+                assert_eq!(function, "limited");
+                assert_eq!(message, Some(0));
+                assert_eq!(max, Duration::from_millis(50));
+            }
+            err => panic!("unexpected {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn session_without_hooks() {
+        // module exports neither hooks nor message buffer
+        let (mut tx, events) = mpsc::channel(4);
+        tx.send(SessionEvent::Connected("wss://host".to_string()))
+            .await
+            .unwrap();
+        tx.send(SessionEvent::Disconnected("closed".to_string()))
+            .await
+            .unwrap();
+        drop(tx);
+        let (frames, mut frames_rx) = watch::channel(None);
+        let session = Session {
+            events,
+            transform: false,
+            handshake: Arc::default(),
+            frames,
+        };
+        let inbound = Some(Inbound::Session(session));
+        let handler = WasmHandler::spawn(module(&[]), settings(Limits::default()), None, inbound);
+        handler.handle.await.unwrap().unwrap();
+        assert_eq!(frames_rx.recv().await, Some(Some(false)));
+    }
+
+    #[test]
+    fn unpack_ack() {
+        let ack = |status: u32, index: u32| ((index as i64) << 32) | status as i64;
+        assert_eq!(
+            Ack::from(ack(0, 0)),
+            Ack {
+                status: Status::Ok,
+                index: 0
+            }
+        );
+        assert_eq!(
+            Ack::from(ack(1, 63)),
+            Ack {
+                status: Status::Retry,
+                index: 63
+            }
+        );
+        assert_eq!(
+            Ack::from(ack(7, u32::max_value())),
+            Ack {
+                status: Status::Unknown(7),
+                index: u32::max_value() as usize
+            }
+        );
+    }
+
+    /// Splits batch the way module does it, every message is prefixed with its u32 LE size
+    fn unpack_batch(mut packed: &[u8]) -> Vec<Vec<u8>> {
+        let mut batch = Vec::new();
+        while !packed.is_empty() {
+            let mut size = [0u8; 4];
+            size.copy_from_slice(&packed[..4]);
+            let size = u32::from_le_bytes(size) as usize;
+            batch.push(packed[4..4 + size].to_vec());
+            packed = &packed[4 + size..];
+        }
+        batch
+    }
+
+    #[test]
+    fn pack_batch_round_trip() {
+        let batch: Vec<Vec<u8>> = vec![b"first".to_vec(), Vec::new(), vec![0; 300]];
+        let packed = pack_batch(&batch);
+        assert_eq!(packed.len(), 5 + 300 + 3 * 4);
+        assert_eq!(unpack_batch(&packed), batch);
+        assert!(pack_batch::<Vec<u8>>(&[]).is_empty());
+    }
+
+    #[test]
+    fn receive_batch_size() {
+        let (tx, rx) = channel::unbounded();
+        for i in 0..5u8 {
+            tx.send(vec![i]).unwrap();
+        }
+        let linger = Duration::from_millis(0);
+        assert_eq!(
+            receive_batch(&rx, 3, linger),
+            Some(vec![vec![0], vec![1], vec![2]])
+        );
+        assert_eq!(receive_batch(&rx, 3, linger), Some(vec![vec![3], vec![4]]));
+        drop(tx);
+        assert_eq!(receive_batch(&rx, 3, linger), None);
+    }
+
+    #[test]
+    fn receive_batch_linger() {
+        let (tx, rx) = channel::unbounded();
+        tx.send(vec![0]).unwrap();
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            tx.send(vec![1]).unwrap();
+        });
+        // message arriving within linger gets into the batch
+        let batch = receive_batch(&rx, 2, Duration::from_secs(5));
+        sender.join().unwrap();
+        assert_eq!(batch, Some(vec![vec![0], vec![1]]));
+    }
+
+    #[cfg(feature = "metering")]
+    #[tokio::test]
+    async fn metering_limit() {
+        // loop, br 0, end
+        let spin = [0x03, 0x40, 0x0c, 0, 0x0b];
+        let limits = Limits {
+            metering: Some(1_000_000),
+            ..Limits::default()
+        };
+        match run(&spin, limits).await {
+            Some(Limit::Metering { max }) => assert_eq!(max, 1_000_000),
+            limit => panic!("unexpected {:?}", limit),
+        }
     }
 }
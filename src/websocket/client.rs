@@ -0,0 +1,143 @@
+use crate::config::{Tls, WebSocketConfig};
+use anyhow::{anyhow, Context, Result};
+use std::borrow::Cow;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{client_async, WebSocketStream};
+use tungstenite::handshake::client::Request;
+use url::Url;
+
+/// Connection to the server, either plain or TLS one
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+pub type WS = WebSocketStream<Box<dyn Io>>;
+
+/// Limit of the proxy response head, CONNECT responses are tiny
+const MAX_PROXY_RESPONSE: usize = 8 * 1024;
+
+/// Client establishing websocket connections with options of the stream:
+/// handshake headers, subprotocols, TLS certificates and HTTP proxy
+pub struct Client {
+    headers: Vec<(String, String)>,
+    protocols: Vec<String>,
+    tls: tokio_tls::TlsConnector,
+    proxy: Option<Url>,
+}
+
+impl Client {
+    /// Client of the stream, fails if any of certificates can't be loaded
+    pub fn new(config: &WebSocketConfig) -> Result<Self> {
+        Ok(Client {
+            headers: config
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            protocols: config.protocols.clone(),
+            tls: tls_connector(&config.tls)?.into(),
+            proxy: config.proxy.clone(),
+        })
+    }
+
+    pub async fn connect(&self, url: &Url) -> Result<WS> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("url {} has no host", url))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| anyhow!("url {} has no port", url))?;
+        let tcp = match self.proxy.as_ref() {
+            Some(proxy) => tunnel(proxy, host, port).await?,
+            None => TcpStream::connect((host, port)).await?,
+        };
+        let stream: Box<dyn Io> = match url.scheme() {
+            "wss" => Box::new(self.tls.connect(host, tcp).await?),
+            "ws" => Box::new(tcp),
+            scheme => return Err(anyhow!("unsupported websocket scheme {}", scheme)),
+        };
+        let mut request = Request::from(url.clone());
+        for (name, value) in self.headers.iter() {
+            request.add_header(Cow::Owned(name.clone()), Cow::Owned(value.clone()));
+        }
+        if !self.protocols.is_empty() {
+            let protocols = Cow::Owned(self.protocols.join(", "));
+            request.add_header(Cow::Borrowed("Sec-WebSocket-Protocol"), protocols);
+        }
+        Ok(client_async(request, stream).await?.0)
+    }
+}
+
+fn tls_connector(tls: &Tls) -> Result<native_tls::TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(path) = tls.ca_file.as_ref() {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Could not read CA certificate {:?}", path))?;
+        let certificate = native_tls::Certificate::from_pem(&pem)
+            .with_context(|| format!("Malformed CA certificate {:?}", path))?;
+        builder.add_root_certificate(certificate);
+    }
+    if let Some(path) = tls.identity.as_ref() {
+        let der = std::fs::read(path)
+            .with_context(|| format!("Could not read client identity {:?}", path))?;
+        let identity = native_tls::Identity::from_pkcs12(&der, &tls.identity_password)
+            .with_context(|| format!("Malformed client identity {:?}", path))?;
+        builder.identity(identity);
+    }
+    Ok(builder.build()?)
+}
+
+/// Opens tunnel to `host:port` via HTTP proxy using CONNECT method,
+/// credentials of the proxy url are sent as basic authorization
+async fn tunnel(proxy: &Url, host: &str, port: u16) -> Result<TcpStream> {
+    let proxy_host = proxy
+        .host_str()
+        .ok_or_else(|| anyhow!("proxy url has no host"))?;
+    let proxy_port = proxy.port_or_known_default().unwrap_or(8080);
+    let mut stream = TcpStream::connect((proxy_host, proxy_port))
+        .await
+        .with_context(|| format!("Could not connect to proxy {}:{}", proxy_host, proxy_port))?;
+
+    let mut request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+        host = host,
+        port = port
+    );
+    if !proxy.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            decode(proxy.username()),
+            decode(proxy.password().unwrap_or(""))
+        );
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode(&credentials)
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // response head is read byte by byte, so that nothing after it is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_PROXY_RESPONSE {
+            return Err(anyhow!("proxy response is too large"));
+        }
+        let mut byte = [0u8];
+        if stream.read(&mut byte).await? == 0 {
+            return Err(anyhow!("proxy closed connection"));
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(stream),
+        _ => Err(anyhow!("proxy refused to connect: {}", status)),
+    }
+}
+
+fn decode(text: &str) -> Cow<str> {
+    percent_encoding::percent_decode_str(text).decode_utf8_lossy()
+}
@@ -1,26 +1,43 @@
+mod client;
 pub mod wasm;
-use wasm::WasmWSInstance;
+mod watchdog;
+pub use client::Client;
+pub use watchdog::{Alert, Watchdog};
+use client::WS;
+use wasm::{Outgoing, WasmWSInstance};
 
+use crate::config::{Secrets, WebSocketConfig};
 use anyhow::anyhow;
+use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use tungstenite::error::Error;
 pub use tungstenite::protocol::Message;
 use url;
 
-type WS = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+/// Max number of handshake messages kept to be sent again on reconnect
+const MAX_HANDSHAKE: usize = 1000;
 
+/// WebSocket connection, it is read and written independently,
+/// so that handshaker can reply while reader waits for the next message
 #[derive(Clone)]
 pub struct WebSocket {
-    pub stream: Arc<Mutex<Option<WS>>>,
+    pub sink: Arc<Mutex<Option<SplitSink<WS, Message>>>>,
+    pub stream: Arc<Mutex<Option<SplitStream<WS>>>>,
+    /// Messages sent by main function of handshaker, those are sent again on reconnect.
+    /// WebSocket is created for every run of the handshaker, so those are never stale
+    handshake: Arc<Mutex<Vec<Vec<u8>>>>,
+    secrets: Secrets,
 }
 
 impl Default for WebSocket {
     fn default() -> Self {
         WebSocket {
+            sink: Arc::new(Mutex::new(None)),
             stream: Arc::new(Mutex::new(None)),
+            handshake: Arc::new(Mutex::new(Vec::new())),
+            secrets: Secrets::default(),
         }
     }
 }
@@ -28,27 +45,111 @@ impl Default for WebSocket {
 use std::ops::DerefMut;
 
 impl WebSocket {
-    pub async fn connect(&self, addr: url::Url) -> Result<(), Error> {
-        let stream = connect_async(addr).await?.0;
+    /// WebSocket which masks given secrets in printed messages
+    pub fn with_secrets(secrets: Secrets) -> Self {
+        WebSocket {
+            secrets,
+            ..Default::default()
+        }
+    }
+
+    /// Connects to `addr` and sends handshake messages sent over previous connection
+    pub async fn connect(&self, client: &Client, addr: &url::Url) -> anyhow::Result<()> {
+        let (mut sink, stream) = client.connect(addr).await?.split();
+        // handshake is locked until connection is in place, so that no message is sent twice
+        let handshake = self.handshake.lock().await;
+        for msg in handshake.iter() {
+            sink.send(Message::Binary(msg.clone())).await?;
+        }
         self.stream.lock().await.replace(stream);
+        self.sink.lock().await.replace(sink);
         Ok(())
     }
 
+    /// Connects to the stream according to its reconnect policy, trying urls in turn,
+    /// returns number of failed attempts and url connected to.
+    /// Every failed attempt is reported to `on_failure`.
+    pub async fn reconnect<'a>(
+        &self,
+        config: &'a WebSocketConfig,
+        mut on_failure: impl FnMut(u32, &url::Url, &anyhow::Error),
+    ) -> anyhow::Result<(u32, &'a url::Url)> {
+        let client = Client::new(config)?;
+        let mut attempt = 0;
+        loop {
+            tokio::time::delay_for(config.reconnect.backoff(attempt)).await;
+            let url = config.url(attempt);
+            match self.connect(&client, url).await {
+                Ok(()) => return Ok((attempt, url)),
+                Err(err) => {
+                    on_failure(attempt, url, &err);
+                    attempt += 1;
+                    if config.reconnect.exhausted(attempt) {
+                        return Err(anyhow!(
+                            "could not connect in {} attempts, last error: {}",
+                            attempt,
+                            err
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends handshaker message, message is kept to be sent again on reconnect
+    /// unless `MAX_HANDSHAKE` messages are kept already.
+    /// Message sent while disconnected is delivered once connection is established.
     pub async fn send_message(&self, msg: Vec<u8>) -> anyhow::Result<()> {
-        println!("{}", std::str::from_utf8(msg.as_slice())?);
-        match self.stream.lock().await.deref_mut() {
-            Some(stream) => Ok(stream.send(Message::Binary(msg)).await?),
+        log::debug!(
+            "handshake message: {}",
+            self.secrets.mask(std::str::from_utf8(msg.as_slice())?)
+        );
+        let mut handshake = self.handshake.lock().await;
+        if handshake.len() < MAX_HANDSHAKE {
+            handshake.push(msg.clone());
+            if handshake.len() == MAX_HANDSHAKE {
+                log::warn!(
+                    "{} handshake messages are kept, further ones are not sent again on reconnect",
+                    MAX_HANDSHAKE
+                );
+            }
+        }
+        if let Some(sink) = self.sink.lock().await.deref_mut() {
+            // failed connection is noticed by reader, message is sent again on reconnect
+            if let Err(err) = sink.send(Message::Binary(msg)).await {
+                log::warn!("failed to send handshake message: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends message of the handshaker session, it is lost when sent while disconnected
+    pub async fn send_session_message(&self, msg: Vec<u8>) -> anyhow::Result<()> {
+        log::debug!(
+            "session message: {}",
+            self.secrets.mask(std::str::from_utf8(msg.as_slice())?)
+        );
+        match self.sink.lock().await.deref_mut() {
+            Some(sink) => Ok(sink.send(Message::Binary(msg)).await?),
             None => Err(anyhow!("tried to send message to disconnected WebSocket")),
         }
     }
 
     pub async fn pong(&self, msg: Vec<u8>) -> anyhow::Result<()> {
-        match self.stream.lock().await.deref_mut() {
-            Some(stream) => Ok(stream.send(Message::Pong(msg)).await?),
+        match self.sink.lock().await.deref_mut() {
+            Some(sink) => Ok(sink.send(Message::Pong(msg)).await?),
             None => Err(anyhow!("tried to send pong to disconnected WebSocket")),
         }
     }
 
+    /// Pings the server, pong is received by reader
+    pub async fn ping(&self, msg: Vec<u8>) -> Result<(), Error> {
+        match self.sink.lock().await.deref_mut() {
+            Some(sink) => sink.send(Message::Ping(msg)).await,
+            None => Err(Error::AlreadyClosed),
+        }
+    }
+
     #[inline]
     pub async fn read(&self) -> Option<Result<Message, Error>> {
         match self.stream.lock().await.deref_mut() {
@@ -58,17 +159,49 @@ impl WebSocket {
     }
 
     pub async fn clean(&self) {
+        self.sink.lock().await.take();
         self.stream.lock().await.take();
     }
 
+    /// Sends messages of the handshaker module to the stream, returns once module stopped
     pub async fn set_handshaker(&self, wasm: &WasmWSInstance) -> anyhow::Result<()> {
         let rx = wasm.clone_receiver();
         loop {
             let rx = rx.clone();
             // Some workaround to wait on sync message from crossbeam while not blocking Tokio
             // TODO: probably whole WASM <-> Tokio communication shall be rethought!
-            let msg = tokio::task::spawn_blocking(move || rx.recv()).await??;
-            self.send_message(msg).await?;
+            let msg = match tokio::task::spawn_blocking(move || rx.recv()).await? {
+                Ok(msg) => msg,
+                // outcome of the module is returned via its handle
+                Err(_) => return Ok(()),
+            };
+            match msg {
+                Outgoing::Handshake(msg) => self.send_message(msg).await?,
+                // session is started over by hooks on reconnect
+                Outgoing::Session(msg) => {
+                    if let Err(err) = self.send_session_message(msg).await {
+                        log::warn!("failed to send session message: {}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WebSocket, MAX_HANDSHAKE};
+
+    #[tokio::test]
+    async fn handshake_record() {
+        let ws = WebSocket::default();
+        // repeated messages are legitimate, e.g. ping of the protocol
+        ws.send_message(b"ping".to_vec()).await.unwrap();
+        ws.send_message(b"ping".to_vec()).await.unwrap();
+        assert_eq!(ws.handshake.lock().await.len(), 2);
+        for _ in 0..MAX_HANDSHAKE {
+            ws.send_message(b"subscribe".to_vec()).await.unwrap();
         }
+        assert_eq!(ws.handshake.lock().await.len(), MAX_HANDSHAKE);
     }
 }
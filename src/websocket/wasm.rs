@@ -1,48 +1,201 @@
-use crate::{wasm::WasmHandle, U8WasmPtr, WasmHandler};
+use crate::log::LogRecord;
+use crate::topic::{topic_imports, Producer};
+use crate::{wasm::Settings, wasm::WasmHandle, U8WasmPtr, WasmHandler};
+use crate::{Inbound, Session, SessionEvent};
 use crossbeam::channel;
+use ipc_orchestrator::message::Message;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
 use wasmer_runtime::{func, imports, Ctx};
 
-type Receiver = channel::Receiver<Vec<u8>>;
+type Receiver = channel::Receiver<Outgoing>;
+
+/// Message handshaker module sends to the stream
+pub enum Outgoing {
+    /// Sent by main function, e.g. subscription, it is sent again on every reconnect
+    Handshake(Vec<u8>),
+    /// Sent by connection hooks as part of the session
+    Session(Vec<u8>),
+}
 
 pub struct WasmWSInstance {
-    inner: WasmHandler,
+    logs: channel::Receiver<LogRecord>,
+    dead_letters: Option<channel::Receiver<Vec<u8>>>,
     rx: Receiver,
+    events: mpsc::Sender<SessionEvent>,
+    /// Whether module handles frames, known once it is instantiated
+    frames: watch::Receiver<Option<bool>>,
+    /// Messages module sent to output topics when it transforms the stream
+    routed: Option<channel::Receiver<Message>>,
 }
 
 impl WasmWSInstance {
     /// spawns WASM module in separate thread
-    /// TODO: This function is panicing on any exception
-    pub fn spawn(wasm_bytes: Vec<u8>, args: Vec<Vec<u8>>) -> Self {
-        let (tx, rx) = channel::bounded::<Vec<u8>>(crate::CHANNEL_SIZE);
+    ///
+    /// Module transforms the stream when output `topics` are provided,
+    /// it is able to send messages to those via topic imports then.
+    /// Failure of the module is returned via handle as `WasmError`, it is awaited
+    /// along with the stream the instance handles.
+    pub fn spawn(
+        wasm_bytes: Vec<u8>,
+        settings: Settings,
+        topics: Option<Vec<String>>,
+    ) -> (Self, WasmHandle) {
+        let (tx, rx) = channel::bounded::<Outgoing>(crate::CHANNEL_SIZE);
+        let (events, events_rx) = mpsc::channel::<SessionEvent>(crate::CHANNEL_SIZE);
+        let (frames_tx, frames) = watch::channel(None);
+        let handshake = Arc::new(AtomicBool::new(false));
 
         // prepare custom imports for wasm
+        // errors are returned to WASM as a trap, which fails the function
+        let in_handshake = handshake.clone();
         let send_websocket_message = move |ctx: &mut Ctx, message_ptr: U8WasmPtr, len: u32| {
             let memory = ctx.memory(0);
-            let message = message_ptr
-                .to_vec(memory, len)
-                .expect("send_websocket_message: failed to get message");
+            let message = message_ptr.to_vec(memory, len).ok_or_else(|| {
+                format!(
+                    "send_websocket_message: pointer {} of {} bytes out of memory bounds",
+                    message_ptr.offset(),
+                    len
+                )
+            })?;
+            let message = if in_handshake.load(Ordering::SeqCst) {
+                Outgoing::Handshake(message)
+            } else {
+                Outgoing::Session(message)
+            };
             tx.send(message)
-                .expect("send_websocket_message: failed to send message");
+                .map_err(|_| "send_websocket_message: failed to send message".to_string())
         };
 
-        let custom_imports = imports! {
+        let mut custom_imports = imports! {
             "io" => {
                 "send_websocket_message" => func!(send_websocket_message),
             },
         };
+        let transform = topics.is_some();
+        let routed = topics.map(|topics| {
+            let (tx, rx) = channel::bounded::<Message>(crate::CHANNEL_SIZE);
+            custom_imports.extend(topic_imports(Producer::new(&settings.source, topics, tx)));
+            rx
+        });
 
-        let inner = WasmHandler::spawn(wasm_bytes, args, Some(custom_imports), false);
+        let session = Session {
+            events: events_rx,
+            transform,
+            handshake,
+            frames: frames_tx,
+        };
+        let inbound = Some(Inbound::Session(session));
+        let inner = WasmHandler::spawn(wasm_bytes, settings, Some(custom_imports), inbound);
 
-        WasmWSInstance { inner, rx }
+        let instance = WasmWSInstance {
+            logs: inner.clone_log_receiver(),
+            dead_letters: inner.clone_dead_letter_receiver(),
+            rx,
+            events,
+            frames,
+            routed,
+        };
+        (instance, inner.into())
     }
 
     pub fn clone_receiver(&self) -> Receiver {
         self.rx.clone()
     }
+
+    pub fn clone_log_receiver(&self) -> channel::Receiver<LogRecord> {
+        self.logs.clone()
+    }
+
+    pub fn clone_dead_letter_receiver(&self) -> Option<channel::Receiver<Vec<u8>>> {
+        self.dead_letters.clone()
+    }
+
+    /// Messages module sent to output topics, when module transforms the stream
+    pub fn clone_topic_receiver(&self) -> Option<channel::Receiver<Message>> {
+        self.routed.clone()
+    }
+
+    /// Whether module handles messages of the stream via `on_ws_message`
+    pub fn handles_messages(&self) -> bool {
+        *self.frames.borrow() == Some(true)
+    }
+
+    /// Waits until hooks of the module are known, or module stopped
+    pub async fn instantiated(&self) {
+        let mut frames = self.frames.clone();
+        while frames.borrow().is_none() {
+            if frames.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Delivers connection event to module hooks, event is dropped once module stopped
+    ///
+    /// Waits while module is busy, so that stream is read no faster than module handles it.
+    pub async fn notify(&self, event: SessionEvent) {
+        let _ = self.events.clone().send(event).await;
+    }
 }
 
-impl Into<WasmHandle> for WasmWSInstance {
-    fn into(self) -> WasmHandle {
-        self.inner.into()
+#[cfg(test)]
+mod tests {
+    use super::WasmWSInstance;
+    use crate::config::{Limits, OnError, Wasi};
+    use crate::{wasm::Settings, SessionEvent};
+    use std::time::Duration;
+
+    /// Transform module, which handles the first envelope sending websocket messages
+    /// in endless loop, so that it stalls once those are not read:
+    /// (import "io" "send_websocket_message" (func (param i32 i32)))
+    /// (memory (export "memory") 1)
+    /// (func (export "_start"))
+    /// (func (export "on_message") (export "on_envelope") (param i32 i32)
+    ///   (loop (call 0 (i32.const 0) (i32.const 0)) (br 0)))
+    /// (func (export "buffer_pointer") (result i32) (i32.const 0))
+    const WASM: &[u8] = b"\0asm\x01\0\0\0\
+        \x01\x0d\x03\x60\0\0\x60\x02\x7f\x7f\0\x60\0\x01\x7f\
+        \x02\x1d\x01\x02io\x16send_websocket_message\0\x01\
+        \x03\x04\x03\0\x01\x02\
+        \x05\x03\x01\0\x01\
+        \x07\x3f\x05\x06memory\x02\0\x06_start\0\x01\x0aon_message\0\x02\
+        \x0bon_envelope\0\x02\x0ebuffer_pointer\0\x03\
+        \x0a\x17\x03\x02\0\x0b\x0d\0\x03\x40\x41\0\x41\0\x10\0\x0c\0\x0b\x0b\x04\0\x41\0\x0b";
+
+    fn settings() -> Settings {
+        Settings {
+            name: "transform".to_string(),
+            source: "transform".to_string(),
+            wasi: Wasi {
+                args: Vec::new(),
+                envs: Vec::new(),
+                preopened: Vec::new(),
+                mapped_dirs: Vec::new(),
+            },
+            limits: Limits::default(),
+            on_error: OnError::default(),
+            dead_letter: None,
+            batch_size: 1,
+            batch_linger: Duration::from_millis(0),
+            counters: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_transform_holds_back_feed() {
+        let (instance, _handle) =
+            WasmWSInstance::spawn(WASM.to_vec(), settings(), Some(Vec::new()));
+        instance.instantiated().await;
+        assert!(!instance.handles_messages());
+        // module is stuck with the first envelope, feed waits for it without blocking the runtime
+        let feed = async {
+            for _ in 0..crate::CHANNEL_SIZE + 2 {
+                instance.notify(SessionEvent::Envelope(Vec::new())).await;
+            }
+        };
+        let res = tokio::time::timeout(Duration::from_millis(100), feed).await;
+        assert!(res.is_err());
     }
 }
@@ -0,0 +1,113 @@
+use super::{Message, WebSocket};
+use crate::config::Heartbeat;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+use tungstenite::error::Error;
+
+/// Heartbeat check tripped by connection which is open but dead
+#[derive(Debug, PartialEq)]
+pub enum Alert {
+    /// Client ping was not answered within pong timeout
+    PongTimeout(Duration),
+    /// No text or binary frame was received within stale threshold
+    Stale(Duration),
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Alert::PongTimeout(timeout) => write!(f, "ping not answered within {:?}", timeout),
+            Alert::Stale(threshold) => write!(f, "no data for {:?}", threshold),
+        }
+    }
+}
+
+impl std::error::Error for Alert {}
+
+/// Reads the stream checking it is alive according to its heartbeat,
+/// it is created for every connection
+pub struct Watchdog {
+    heartbeat: Heartbeat,
+    last_data: Instant,
+    next_ping: Option<Instant>,
+    /// Deadline of the pong for the earliest unanswered ping
+    pong_deadline: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn new(heartbeat: &Heartbeat) -> Self {
+        let now = Instant::now();
+        Watchdog {
+            heartbeat: heartbeat.clone(),
+            last_data: now,
+            next_ping: heartbeat.ping_interval().map(|interval| now + interval),
+            pong_deadline: None,
+        }
+    }
+
+    /// Next frame of the stream, pings are sent while waiting for it.
+    /// Fails once any of heartbeat checks trips, connection is not closed then.
+    pub async fn read(&mut self, ws: &WebSocket) -> Result<Option<Result<Message, Error>>, Alert> {
+        loop {
+            let now = Instant::now();
+            self.check(now)?;
+            if matches!(self.next_ping, Some(next_ping) if now >= next_ping) {
+                // failed connection is reported as the frame, same as reader sees it
+                if let Err(err) = ws.ping(Vec::new()).await {
+                    return Ok(Some(Err(err)));
+                }
+                if self.pong_deadline.is_none() {
+                    self.pong_deadline = self.heartbeat.pong_timeout().map(|timeout| now + timeout);
+                }
+                self.next_ping = self
+                    .heartbeat
+                    .ping_interval()
+                    .map(|interval| now + interval);
+            }
+            let deadline = [self.next_ping, self.pong_deadline, self.stale_deadline()]
+                .iter()
+                .flatten()
+                .min()
+                .copied();
+            let msg = match deadline {
+                Some(deadline) => match timeout_at(deadline, ws.read()).await {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                },
+                None => ws.read().await,
+            };
+            match msg {
+                Some(Ok(Message::Pong(_))) => self.pong_deadline = None,
+                Some(Ok(Message::Text(_))) | Some(Ok(Message::Binary(_))) => {
+                    self.last_data = Instant::now()
+                }
+                _ => (),
+            }
+            return Ok(msg);
+        }
+    }
+
+    fn stale_deadline(&self) -> Option<Instant> {
+        self.heartbeat
+            .stale_after()
+            .map(|threshold| self.last_data + threshold)
+    }
+
+    fn check(&self, now: Instant) -> Result<(), Alert> {
+        if let (Some(deadline), Some(timeout)) = (self.pong_deadline, self.heartbeat.pong_timeout())
+        {
+            if now >= deadline {
+                return Err(Alert::PongTimeout(timeout));
+            }
+        }
+        if let (Some(deadline), Some(threshold)) =
+            (self.stale_deadline(), self.heartbeat.stale_after())
+        {
+            if now >= deadline {
+                return Err(Alert::Stale(threshold));
+            }
+        }
+        Ok(())
+    }
+}